@@ -0,0 +1,153 @@
+//! Derive macros for llua
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Fields, Index, LitStr};
+
+/// Options specified by the `#[llua(...)]` attributes
+#[derive(Default)]
+struct Attrs {
+    rename: Option<String>,
+    skip: bool,
+}
+
+impl Attrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut result = Self::default();
+        for attr in attrs.iter().filter(|a| a.path().is_ident("llua")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    result.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("skip") {
+                    result.skip = true;
+                } else {
+                    return Err(meta.error("unknown llua attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(result)
+    }
+}
+
+/// Derives `FromLua`, a fieldless enum is converted from the name of its variant,
+/// which can be renamed by `#[llua(rename = "...")]`, and other types are deserialized by serde
+#[proc_macro_derive(FromLua, attributes(llua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_lua(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn from_lua(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    match &input.data {
+        Data::Enum(data) if data.variants.iter().all(|v| v.fields.is_empty()) => {
+            let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+            let mut names = Vec::new();
+            let mut arms = Vec::new();
+            for variant in data.variants.iter() {
+                let attrs = Attrs::parse(&variant.attrs)?;
+                if attrs.skip {
+                    continue;
+                }
+                let ident = &variant.ident;
+                let key = attrs.rename.unwrap_or_else(|| ident.to_string());
+                arms.push(quote!(#key => Self::#ident,));
+                names.push(key);
+            }
+            let type_name = names.join("|");
+            Ok(quote! {
+                impl #impl_generics ::llua::FromLua<'_> for #name #ty_generics #where_clause {
+                    const TYPE_NAME: &'static str = #type_name;
+
+                    fn from_lua(s: &::llua::State, i: ::llua::Index) -> Option<Self> {
+                        Some(match <&str as ::llua::FromLua>::from_lua(s, i)? {
+                            #(#arms)*
+                            _ => return None,
+                        })
+                    }
+                }
+            })
+        }
+        _ => {
+            let mut generics = input.generics.clone();
+            generics.params.insert(0, syn::parse_quote!('__llua));
+            let (impl_generics, _, _) = generics.split_for_impl();
+            let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+            let mut where_clause = where_clause
+                .cloned()
+                .unwrap_or_else(|| syn::parse_quote!(where));
+            where_clause
+                .predicates
+                .push(syn::parse_quote!(Self: ::llua::__private::serde::de::DeserializeOwned + '__llua));
+            Ok(quote! {
+                impl #impl_generics ::llua::FromLua<'__llua> for #name #ty_generics #where_clause {
+                    fn from_lua(s: &'__llua ::llua::State, i: ::llua::Index) -> Option<Self> {
+                        <::llua::SerdeValue<Self> as ::llua::FromLua>::from_lua(s, i).map(|v| v.0)
+                    }
+                }
+            })
+        }
+    }
+}
+
+/// Derives `ToLua` for a struct, which is converted to a table with a field per member,
+/// a member can be renamed by `#[llua(rename = "...")]` or skipped by `#[llua(skip)]`,
+/// and the members of a tuple struct are stored as an array
+#[proc_macro_derive(ToLua, attributes(llua))]
+pub fn derive_to_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    to_lua(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn to_lua(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let data = match &input.data {
+        Data::Struct(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "ToLua can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut sets = Vec::new();
+    for (i, field) in data.fields.iter().enumerate() {
+        let attrs = Attrs::parse(&field.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+        sets.push(match &field.ident {
+            Some(ident) => {
+                let key = attrs.rename.unwrap_or_else(|| ident.to_string());
+                quote!(t.set(#key, self.#ident);)
+            }
+            None => {
+                let member = Index::from(i);
+                let n = sets.len() as i64 + 1;
+                quote!(t.seti(#n, self.#member);)
+            }
+        });
+    }
+    let count = sets.len() as i32;
+    let (narr, nrec) = match data.fields {
+        Fields::Unnamed(_) => (count, 0),
+        _ => (0, count),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::llua::ToLua for #name #ty_generics #where_clause {
+            fn to_lua(self, s: &::llua::State) {
+                let t = s.table(#narr, #nrec);
+                #(#sets)*
+            }
+        }
+    })
+}
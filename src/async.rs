@@ -11,17 +11,19 @@ impl UserData for TaskWrapper<'_> {
 }
 
 impl State {
-    // #[inline(always)]
-    // pub async fn call_async<'a, T: ToLuaMulti, R: FromLuaMulti<'a>>(
-    //     &self,
-    //     args: T,
-    // ) -> Result<R, Error> {
-    //     let co = Coroutine::with_fn(self, -1);
-    //     // TODO: Coroutine with lifetime
-    //     // TODO: FromLuaOwned
-    //     let co: &'a Coroutine = unsafe { core::mem::transmute(&co) };
-    //     co.call_async::<T, R>(args, Some(self)).await
-    // }
+    /// [-1, +R::COUNT] Calls the function on the top of stack in a new coroutine,
+    /// the results are moved into this state's stack
+    pub async fn call_async<'a, T: ToLuaMulti, R: FromLuaMulti<'a>>(
+        &'a self,
+        args: T,
+    ) -> Result<R, Error> {
+        let co = ScopedCoroutine::with_fn(self, -1);
+        self.pop(1);
+        let count = R::COUNT as i32;
+        co.raw_call_async(Some(self), co.pushx(args), count).await?;
+        co.xmove(self, count);
+        R::from_lua(self, self.abs_index(-count)).ok_or(Error::ConvertFailed)
+    }
 
     #[inline(always)]
     pub(crate) fn yield_task<'a, RET: ToLuaMulti, F: Future<Output = RET> + 'a>(
@@ -61,6 +63,25 @@ impl State {
         panic!("co_yieldk called in non-coroutine context; check is_yieldable first")
     }
 
+    /// Yields `args` to the resumer, the values passed to the next resume are converted to `R` and given
+    /// to the continuation `k`, whose results are returned to the caller of this function.
+    /// Yielding discards the rust frame, so the code after the yield must be written in `k`
+    pub fn yield_values<A, R, O, K>(&self, args: A, k: K) -> !
+    where
+        A: ToLuaMulti,
+        R: for<'b> FromLuaMulti<'b>,
+        O: ToLuaMulti,
+        K: FnOnce(&State, R) -> O,
+    {
+        let top = self.get_top();
+        self.yieldk(self.pushx(args), move |s, _| {
+            match R::from_lua(s, top + 1) {
+                Some(r) => s.pushx(k(s, r)),
+                None => s.error_string("the resumed values don't match"),
+            }
+        })
+    }
+
     /// Maps to `lua_yieldk`.
     pub fn yieldk<F>(&self, nresults: c_int, continuation: F) -> !
     where
@@ -73,6 +94,19 @@ impl State {
 }
 
 impl Coroutine {
+    /// Drops the futures of the tasks left on the stack by a suspended async call,
+    /// so their destructors run now instead of when the coroutine is collected
+    pub(crate) fn drop_pending_tasks(&self) {
+        for i in 1..=self.get_top() {
+            if self.type_of(i) != Type::Userdata {
+                continue;
+            }
+            if let Some(task) = self.arg::<&mut TaskWrapper>(i) {
+                task.0.take();
+            }
+        }
+    }
+
     #[inline(always)]
     pub async fn call_async<'a, T: ToLuaMulti, R: FromLuaMulti<'a>>(
         &'a self,
@@ -149,3 +183,236 @@ where
 {
     core::mem::transmute::<_, Box<F>>(ctx)(&State::from_ptr(st), ThreadStatus::from_c_int(status))
 }
+
+#[cfg(feature = "std")]
+mod channel {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use core::pin::Pin;
+    use core::task::{Context, Poll, Waker};
+    use std::sync::Mutex;
+
+    struct Chan<T> {
+        queue: VecDeque<T>,
+        waker: Option<Waker>,
+        senders: usize,
+        receiver: bool,
+    }
+
+    /// Creates an unbounded channel, whose receiver can be awaited in lua by `receiver:recv()`
+    /// in an async task, and the sender is usable from rust or from lua by `sender:send(v)`
+    pub fn channel<T>() -> (LuaSender<T>, LuaReceiver<T>) {
+        let chan = Arc::new(Mutex::new(Chan {
+            queue: VecDeque::new(),
+            waker: None,
+            senders: 1,
+            receiver: true,
+        }));
+        (LuaSender(chan.clone()), LuaReceiver(chan))
+    }
+
+    pub struct LuaSender<T>(Arc<Mutex<Chan<T>>>);
+
+    pub struct LuaReceiver<T>(Arc<Mutex<Chan<T>>>);
+
+    /// The future returned by [`LuaReceiver::recv`], resolved to `None` if all the senders are dropped
+    pub struct Recv<T>(Arc<Mutex<Chan<T>>>);
+
+    impl<T> LuaSender<T> {
+        /// Sends a message, returns it back if the receiver was dropped
+        pub fn send(&self, v: T) -> Result<(), T> {
+            let mut chan = self.0.lock().unwrap();
+            if !chan.receiver {
+                return Err(v);
+            }
+            chan.queue.push_back(v);
+            if let Some(waker) = chan.waker.take() {
+                waker.wake();
+            }
+            Ok(())
+        }
+    }
+
+    impl<T> Clone for LuaSender<T> {
+        fn clone(&self) -> Self {
+            self.0.lock().unwrap().senders += 1;
+            Self(self.0.clone())
+        }
+    }
+
+    impl<T> Drop for LuaSender<T> {
+        fn drop(&mut self) {
+            let mut chan = self.0.lock().unwrap();
+            chan.senders -= 1;
+            if chan.senders == 0 {
+                if let Some(waker) = chan.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    impl<T> LuaReceiver<T> {
+        pub fn recv(&self) -> Recv<T> {
+            Recv(self.0.clone())
+        }
+
+        pub fn try_recv(&self) -> Option<T> {
+            self.0.lock().unwrap().queue.pop_front()
+        }
+    }
+
+    impl<T> Drop for LuaReceiver<T> {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().receiver = false;
+        }
+    }
+
+    impl<T> Future for Recv<T> {
+        type Output = Option<T>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut chan = self.0.lock().unwrap();
+            match chan.queue.pop_front() {
+                Some(v) => Poll::Ready(Some(v)),
+                None if chan.senders == 0 => Poll::Ready(None),
+                None => {
+                    chan.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    impl<T: for<'a> FromLua<'a> + 'static> UserData for LuaSender<T> {
+        const TYPE_NAME: &'static str = "llua::LuaSender";
+
+        fn methods(mt: &ValRef) {
+            mt.register("send", |this: &Self, v: T| this.send(v).is_ok());
+            mt.register("clone", |this: &Self| this.clone());
+        }
+    }
+
+    impl<T: ToLua + 'static> UserData for LuaReceiver<T> {
+        const TYPE_NAME: &'static str = "llua::LuaReceiver";
+
+        fn methods(mt: &ValRef) {
+            mt.register("recv", |s: StateRef<'static>| {
+                <&Self as FromLua>::check(&s, 1).recv()
+            });
+            mt.register("try_recv", |this: &Self| this.try_recv());
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use channel::*;
+
+mod mutex {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+    use core::pin::Pin;
+    use core::task::{Context, Poll, Waker};
+
+    #[derive(Default)]
+    struct Inner {
+        locked: bool,
+        waiters: VecDeque<Waker>,
+    }
+
+    /// Creates a mutex for the async tasks running on the same thread, which can be awaited
+    /// in lua by `mutex:lock()` without blocking the executor
+    pub fn async_mutex() -> AsyncMutex {
+        AsyncMutex::default()
+    }
+
+    #[derive(Clone, Default)]
+    pub struct AsyncMutex(Rc<RefCell<Inner>>);
+
+    /// The future returned by [`AsyncMutex::lock`]
+    pub struct Lock(Rc<RefCell<Inner>>);
+
+    /// Releases the lock when dropped, or by `guard:unlock()` and `<close>` variables in lua
+    pub struct AsyncMutexGuard(Option<Rc<RefCell<Inner>>>);
+
+    impl AsyncMutex {
+        pub fn lock(&self) -> Lock {
+            Lock(self.0.clone())
+        }
+
+        pub fn try_lock(&self) -> Option<AsyncMutexGuard> {
+            let mut inner = RefCell::borrow_mut(&self.0);
+            if inner.locked {
+                return None;
+            }
+            inner.locked = true;
+            Some(AsyncMutexGuard(Some(self.0.clone())))
+        }
+
+        pub fn is_locked(&self) -> bool {
+            RefCell::borrow(&self.0).locked
+        }
+    }
+
+    impl Future for Lock {
+        type Output = AsyncMutexGuard;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut inner = RefCell::borrow_mut(&self.0);
+            if inner.locked {
+                inner.waiters.push_back(cx.waker().clone());
+                Poll::Pending
+            } else {
+                inner.locked = true;
+                Poll::Ready(AsyncMutexGuard(Some(self.0.clone())))
+            }
+        }
+    }
+
+    impl AsyncMutexGuard {
+        pub fn unlock(&mut self) {
+            if let Some(inner) = self.0.take() {
+                let mut inner = RefCell::borrow_mut(&inner);
+                inner.locked = false;
+                // the woken tasks race for the lock, the losers wait again
+                inner.waiters.drain(..).for_each(Waker::wake);
+            }
+        }
+    }
+
+    impl Drop for AsyncMutexGuard {
+        fn drop(&mut self) {
+            self.unlock();
+        }
+    }
+
+    impl UserData for AsyncMutex {
+        const TYPE_NAME: &'static str = "llua::AsyncMutex";
+
+        fn methods(mt: &ValRef) {
+            mt.register("lock", |s: StateRef<'static>| {
+                <&Self as FromLua>::check(&s, 1).lock()
+            });
+            mt.register("try_lock", Self::try_lock);
+            mt.register("is_locked", Self::is_locked);
+        }
+    }
+
+    impl UserData for AsyncMutexGuard {
+        const TYPE_NAME: &'static str = "llua::AsyncMutexGuard";
+        const CLOSE: bool = true;
+
+        fn methods(mt: &ValRef) {
+            mt.register("unlock", Self::unlock);
+        }
+
+        fn close(&mut self, s: &State) {
+            self.unlock();
+        }
+    }
+}
+
+pub use mutex::*;
@@ -2,10 +2,18 @@
 pub mod regex;
 #[cfg(feature = "std")]
 pub mod std;
+#[cfg(feature = "toml")]
+pub mod toml;
+#[cfg(feature = "yaml")]
+pub mod yaml;
 
 pub fn init_global(s: &crate::State) {
     #[cfg(feature = "std")]
     self::std::init_global(s);
     #[cfg(feature = "regex")]
     s.requiref(crate::cstr!("regex"), regex::open, false);
+    #[cfg(feature = "toml")]
+    s.requiref(crate::cstr!("toml"), toml::open, false);
+    #[cfg(feature = "yaml")]
+    s.requiref(crate::cstr!("yaml"), yaml::open, false);
 }
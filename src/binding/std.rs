@@ -133,6 +133,116 @@ pub mod time {
     }
 }
 
+pub mod encoding {
+    use super::*;
+    use serde_bytes::ByteBuf;
+
+    const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    pub fn base64_encode(data: &[u8]) -> String {
+        let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+        for chunk in data.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - i * 8));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(BASE64[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    /// Decodes standard base64, the trailing padding is optional
+    pub fn base64_decode(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let data = data
+            .strip_suffix(b"==")
+            .or_else(|| data.strip_suffix(b"="))
+            .unwrap_or(data);
+        if data.len() % 4 == 1 {
+            return Err("invalid base64 length");
+        }
+        let mut out = Vec::with_capacity(data.len() * 3 / 4);
+        for chunk in data.chunks(4) {
+            let mut n = 0u32;
+            for (i, &c) in chunk.iter().enumerate() {
+                let v = BASE64
+                    .iter()
+                    .position(|&b| b == c)
+                    .ok_or("invalid base64 character")?;
+                n |= (v as u32) << (18 - i * 6);
+            }
+            for i in 0..chunk.len() - 1 {
+                out.push((n >> (16 - i * 8)) as u8);
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn hex_encode(data: &[u8]) -> String {
+        let mut out = String::with_capacity(data.len() * 2);
+        for &b in data {
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0xf) as usize] as char);
+        }
+        out
+    }
+
+    pub fn hex_decode(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if !data.len().is_multiple_of(2) {
+            return Err("invalid hex length");
+        }
+        let digit = |c: u8| {
+            (c as char)
+                .to_digit(16)
+                .map(|d| d as u8)
+                .ok_or("invalid hex character")
+        };
+        data.chunks(2)
+            .map(|c| Ok(digit(c[0])? << 4 | digit(c[1])?))
+            .collect()
+    }
+
+    pub fn init(s: &State) {
+        let t = s.table(0, 2);
+        t.register("encode", base64_encode);
+        t.register("decode", |s: &State, data: &[u8]| {
+            ByteBuf::from(base64_decode(data).unwrap_or_else(|e| s.error_string(e)))
+        });
+        s.set_global(cstr!("base64"));
+
+        let t = s.table(0, 2);
+        t.register("encode", hex_encode);
+        t.register("decode", |s: &State, data: &[u8]| {
+            ByteBuf::from(hex_decode(data).unwrap_or_else(|e| s.error_string(e)))
+        });
+        s.set_global(cstr!("hex"));
+    }
+}
+
+#[cfg(feature = "hashing")]
+pub mod hash {
+    use super::*;
+    use sha2::Digest;
+
+    pub fn init(s: &State) {
+        let t = s.table(0, 3);
+        t.register("sha256", |data: &[u8]| {
+            encoding::hex_encode(&sha2::Sha256::digest(data))
+        });
+        t.register("md5", |data: &[u8]| {
+            encoding::hex_encode(&md5::Md5::digest(data))
+        });
+        t.register("crc32", crc32fast::hash);
+        s.set_global(cstr!("hash"));
+    }
+}
+
 pub mod process {
     use super::*;
     use std::io::{Read, Write};
@@ -178,7 +288,7 @@ pub mod process {
                 this.arg(arg);
                 StackRef(1)
             });
-            mt.register("args", |this: &mut Self, arg: SerdeValue<Vec<&str>>| {
+            mt.register("args", |this: &mut Self, arg: SerdeValue<Vec<String>>| {
                 this.args(arg.as_slice());
                 StackRef(1)
             });
@@ -211,6 +321,34 @@ pub mod process {
                 StackRef(1)
             });
             mt.register("spawn", Self::spawn);
+            // waits for the child in another thread, so the async task is suspended instead of blocking.
+            // Like `Command::output`, stdout and stderr are piped to be captured, but the pipes are set
+            // on this command itself, so they stay piped for its later `spawn`
+            #[cfg(feature = "async")]
+            mt.register("output_async", |s: StateRef<'static>| {
+                let this = <&mut Self as FromLua>::check(&s, 1);
+                let (tx, rx) = channel();
+                match this.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+                    Ok(child) => {
+                        std::thread::spawn(move || tx.send(child.wait_with_output()));
+                    }
+                    Err(err) => {
+                        tx.send(Err(err)).ok();
+                    }
+                }
+                async move {
+                    let output = rx.recv().await.ok_or("the waiting thread exited")?;
+                    output
+                        .map(|o| {
+                            let (stdout, stderr) = (
+                                serde_bytes::ByteBuf::from(o.stdout),
+                                serde_bytes::ByteBuf::from(o.stderr),
+                            );
+                            (o.status.success(), o.status.code(), stdout, stderr)
+                        })
+                        .map_err(|e| e.to_string())
+                }
+            });
         }
     }
 
@@ -340,7 +478,7 @@ pub fn extend_os(s: &State) {
     use std::process::{Command, Stdio};
 
     fn init_command(arg: ValRef) -> Command {
-        let mut args: SerdeValue<Vec<&str>> = arg.check_cast();
+        let mut args: SerdeValue<Vec<String>> = arg.check_cast();
         if args.is_empty() {
             arg.state.error_string("empty command");
         }
@@ -351,7 +489,7 @@ pub fn extend_os(s: &State) {
         args.getopt::<_, Stdio>("stdout").map(|v| cmd.stdout(v));
         args.getopt::<_, Stdio>("stderr").map(|v| cmd.stderr(v));
         args.getopt::<_, &str>("cwd").map(|v| cmd.current_dir(v));
-        args.getopt::<_, SerdeValue<HashMap<&str, &str>>>("env")
+        args.getopt::<_, SerdeValue<HashMap<String, String>>>("env")
             .map(|v| {
                 for (k, val) in v.iter() {
                     cmd.env(k, val);
@@ -371,6 +509,62 @@ pub fn extend_os(s: &State) {
         "spawn_child",
         RsFn::new(|s: &State| init_command(s.val(1)).spawn()),
     );
+    // returns `(success, code)`, `code` is the exit code of the shell, or the negated signal
+    // number if it was killed by a signal. The command is interpreted by the platform shell,
+    // so never build it from untrusted input, use `os.command` with an argument list instead
+    os.register("execute", |s: &State, cmd: Option<&str>| {
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => return s.pushed(true),
+        };
+        #[cfg(target_os = "windows")]
+        let status = Command::new("cmd").args(["/C", cmd]).status();
+        #[cfg(not(target_os = "windows"))]
+        let status = Command::new("sh").args(["-c", cmd]).status();
+        match status {
+            Ok(status) => {
+                #[cfg(unix)]
+                if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+                    return s.pushed((false, -signal));
+                }
+                s.pushed((status.success(), status.code().unwrap_or(-1)))
+            }
+            Err(err) => s.pushed((NilVal, err.to_string())),
+        }
+    });
+}
+
+/// `io.read_line` and `io.read_all` read the stdin of the process, the `io` table is created if absent
+pub fn extend_io(s: &State) {
+    use std::io::{BufRead, Read};
+
+    if s.get_global(cstr!("io")) != Type::Table {
+        s.pop(1);
+        s.new_table();
+        s.push_value(-1);
+        s.set_global(cstr!("io"));
+    }
+    let io = s.val(-1);
+    // a line without the line break, nil at the end of file
+    io.register("read_line", || {
+        let mut line = Vec::new();
+        if std::io::stdin().lock().read_until(b'\n', &mut line)? == 0 {
+            return std::io::Result::Ok(None);
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        Ok(Some(serde_bytes::ByteBuf::from(line)))
+    });
+    io.register("read_all", || {
+        let mut data = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut data)?;
+        std::io::Result::Ok(serde_bytes::ByteBuf::from(data))
+    });
+    s.pop(1);
 }
 
 pub fn extend_string(s: &State) {
@@ -433,16 +627,160 @@ mod thread {
     use std::thread::{self, JoinHandle};
     use std::time::Duration;
 
+    /// An owned copy of a lua value, used to move results across states
+    enum Transfer {
+        Nil,
+        Bool(bool),
+        Int(i64),
+        Num(f64),
+        Str(Vec<u8>),
+        Seq(Vec<Transfer>),
+        Map(Vec<(Transfer, Transfer)>),
+    }
+
+    impl<'de> ::serde::Deserialize<'de> for Transfer {
+        fn deserialize<D: ::serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            use ::serde::de::{MapAccess, SeqAccess, Visitor};
+
+            struct TransferVisitor;
+
+            impl<'de> Visitor<'de> for TransferVisitor {
+                type Value = Transfer;
+
+                fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                    f.write_str("serializable lua value")
+                }
+
+                fn visit_unit<E>(self) -> Result<Transfer, E> {
+                    Ok(Transfer::Nil)
+                }
+
+                fn visit_bool<E>(self, v: bool) -> Result<Transfer, E> {
+                    Ok(Transfer::Bool(v))
+                }
+
+                fn visit_i64<E>(self, v: i64) -> Result<Transfer, E> {
+                    Ok(Transfer::Int(v))
+                }
+
+                fn visit_u64<E>(self, v: u64) -> Result<Transfer, E> {
+                    // lua integers are signed, keep the magnitude of the larger ones as a float
+                    Ok(i64::try_from(v).map_or(Transfer::Num(v as f64), Transfer::Int))
+                }
+
+                fn visit_f64<E>(self, v: f64) -> Result<Transfer, E> {
+                    Ok(Transfer::Num(v))
+                }
+
+                fn visit_str<E>(self, v: &str) -> Result<Transfer, E> {
+                    Ok(Transfer::Str(v.as_bytes().to_vec()))
+                }
+
+                fn visit_bytes<E>(self, v: &[u8]) -> Result<Transfer, E> {
+                    Ok(Transfer::Str(v.to_vec()))
+                }
+
+                fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Transfer, A::Error> {
+                    let mut result = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                    while let Some(v) = seq.next_element()? {
+                        result.push(v);
+                    }
+                    Ok(Transfer::Seq(result))
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Transfer, A::Error> {
+                    let mut result = Vec::new();
+                    while let Some(kv) = map.next_entry()? {
+                        result.push(kv);
+                    }
+                    Ok(Transfer::Map(result))
+                }
+            }
+
+            d.deserialize_any(TransferVisitor)
+        }
+    }
+
+    impl ::serde::Serialize for Transfer {
+        fn serialize<S: ::serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            use ::serde::ser::{SerializeMap, SerializeSeq};
+
+            match self {
+                Self::Nil => s.serialize_unit(),
+                Self::Bool(v) => s.serialize_bool(*v),
+                Self::Int(v) => s.serialize_i64(*v),
+                Self::Num(v) => s.serialize_f64(*v),
+                Self::Str(v) => s.serialize_bytes(v),
+                Self::Seq(v) => {
+                    let mut seq = s.serialize_seq(Some(v.len()))?;
+                    for e in v.iter() {
+                        seq.serialize_element(e)?;
+                    }
+                    seq.end()
+                }
+                Self::Map(v) => {
+                    let mut map = s.serialize_map(Some(v.len()))?;
+                    for (k, v) in v.iter() {
+                        map.serialize_entry(k, v)?;
+                    }
+                    map.end()
+                }
+            }
+        }
+    }
+
+    /// owned return values of a thread routine
+    type ThreadResult = Result<Vec<Transfer>, String>;
+
     struct LLuaThread {
         handle: RawHandle,
-        join: Option<JoinHandle<()>>,
+        join: Option<JoinHandle<ThreadResult>>,
     }
 
     impl LLuaThread {
         #[inline]
-        fn get(&self) -> Result<&JoinHandle<()>, &'static str> {
+        fn get(&self) -> Result<&JoinHandle<ThreadResult>, &'static str> {
             self.join.as_ref().ok_or("thread joined")
         }
+
+        /// source of the routine function on the top of the stack
+        #[cfg(feature = "tracing")]
+        fn chunk_name(routine: &Coroutine) -> String {
+            let mut ar: ffi::lua_Debug = unsafe { core::mem::zeroed() };
+            routine.push_value(-1);
+            routine.get_info(cstr!(">S"), &mut ar);
+            unsafe { std::ffi::CStr::from_ptr(ar.short_src.as_ptr()) }
+                .to_string_lossy()
+                .into()
+        }
+
+        /// call the routine and copy out all of its results, which will be pushed into the joining state
+        fn run(routine: &Coroutine) -> ThreadResult {
+            let top = routine.get_top() - 1;
+            routine.push_fn(Some(State::traceback_c));
+            routine.insert(top + 1);
+            let result = if routine.pcall(0, ffi::LUA_MULTRET, top + 1).is_ok() {
+                (top + 2..=routine.get_top())
+                    .map(|i| match routine.type_of(i) {
+                        Type::Function | Type::Userdata | Type::LightUserdata | Type::Thread => {
+                            Err(std::format!(
+                                "return value #{} is not serializable: {}",
+                                i - top - 1,
+                                routine.typename_at(i)
+                            ))
+                        }
+                        _ => routine
+                            .val(i)
+                            .deserialize::<Transfer>()
+                            .map_err(|e| e.to_string()),
+                    })
+                    .collect()
+            } else {
+                Err(routine.to_str(-1).unwrap_or("<error>").to_string())
+            };
+            routine.set_top(top);
+            result
+        }
     }
 
     impl UserData for LLuaThread {
@@ -459,9 +797,19 @@ mod thread {
         }
 
         fn methods(mt: &ValRef) {
-            mt.register("join", |this: &mut Self| {
-                this.join.take().ok_or("thread joined").map(|j| j.join())
-            });
+            // the results are copied out through serde, the join raises an error instead
+            // if the routine returns a function, userdata or coroutine
+            mt.register(
+                "join",
+                |s: &State, this: &mut Self| -> Result<Pushed, String> {
+                    let join = this.join.take().ok_or("thread joined")?;
+                    let values = join.join().map_err(|_| "thread panicked")??;
+                    for v in values.iter() {
+                        s.push_serialize(v).map_err(|e| e.to_string())?;
+                    }
+                    Ok(Pushed(values.len() as _))
+                },
+            );
             mt.register("unpark", |this: &Self| {
                 this.get().map(|j| j.thread().unpark())
             });
@@ -479,14 +827,16 @@ mod thread {
 
     impl UserData for LLuaMutexGaurd {
         const TYPE_NAME: &'static str = "LLuaMutexGaurd";
+        const CLOSE: bool = true;
+
+        fn close(&mut self, s: &State) {
+            self.0.take();
+        }
 
         fn methods(mt: &ValRef) {
-            fn unlock(this: &mut LLuaMutexGaurd) {
+            mt.register("unlock", |this: &mut Self| {
                 this.0.take();
-            }
-
-            mt.register("unlock", unlock);
-            mt.register("__close", unlock);
+            });
         }
     }
 
@@ -566,7 +916,16 @@ mod thread {
                 b = b.name(name.into());
             }
             b.spawn(move || {
-                if let Err(err) = routine.pcall_trace::<_, ()>(()) {
+                #[cfg(feature = "tracing")]
+                let _span = tracing::error_span!(
+                    "llua::thread",
+                    id = thread::current().id().as_u64().get(),
+                    name = thread::current().name().unwrap_or_default(),
+                    chunk = %LLuaThread::chunk_name(&routine),
+                )
+                .entered();
+                let result = LLuaThread::run(&routine);
+                if let Err(err) = result.as_ref() {
                     call_print(
                         &routine,
                         &std::format!(
@@ -577,6 +936,7 @@ mod thread {
                         ),
                     );
                 }
+                result
             })
             .map(|join| {
                 #[cfg(target_os = "windows")]
@@ -609,7 +969,11 @@ mod thread {
 
 pub fn init_global(s: &State) {
     extend_os(s);
+    extend_io(s);
     extend_string(s);
+    encoding::init(s);
+    #[cfg(feature = "hashing")]
+    hash::init(s);
     #[cfg(feature = "thread")]
     thread::init(s);
 
@@ -644,6 +1008,9 @@ pub fn call_print(s: &State, err: &str) {
         s.push(err);
         s.pcall(1, 0, 0);
     } else {
+        #[cfg(feature = "tracing")]
+        tracing::error!("[callback error] {}", err);
+        #[cfg(not(feature = "tracing"))]
         std::eprintln!("[callback error] {}", err);
     }
 }
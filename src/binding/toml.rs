@@ -0,0 +1,34 @@
+use crate::{ffi::lua_State, *};
+
+/// The number keys are encoded as strings, and the keys are sorted to get a stable output
+pub fn encode(val: ValRef) -> Result<String, ::toml::ser::Error> {
+    let options = SerializeOptions {
+        sort_keys: true,
+        string_keys: true,
+        ..Default::default()
+    };
+    ::toml::to_string(&val.serialize_with(options))
+}
+
+/// [-0, +1, -] Pushes the table decoded from the toml document
+pub fn decode(s: &State, src: &str) -> Result<(), ::toml::de::Error> {
+    s.push_from_deserializer(::toml::Deserializer::new(src))
+}
+
+/// The `luaopen_*` style loader of the `toml` module, returns the table of `encode` and `decode`
+///
+/// # Safety
+///
+/// `l` must be a valid pointer to a running lua state
+pub unsafe extern "C" fn open(l: *mut lua_State) -> i32 {
+    let s = State::from_ptr(l);
+    let t = s.table(0, 2);
+    t.register("encode", |s: &State| {
+        encode(s.val(1)).unwrap_or_else(|e| s.error_string(e.to_string()))
+    });
+    t.register("decode", |s: &State, src: &str| {
+        decode(s, src).unwrap_or_else(|e| s.error_string(e.to_string()));
+        Pushed(1)
+    });
+    1
+}
@@ -0,0 +1,240 @@
+//! Thin wrappers named after the common [mlua](https://docs.rs/mlua) entry points,
+//! to migrate a codebase incrementally.
+//!
+//! The handles are still stack based: the methods creating a value push it and return a [`ValRef`]
+//! to the slot, unlike the registry backed handles of mlua
+
+use crate::{error::Error, *};
+use alloc::format;
+use core::ops::Deref;
+use libc::c_int;
+
+/// An owned [`State`] with the standard libraries opened, which is closed when dropped
+pub struct Lua(State);
+
+impl Deref for Lua {
+    type Target = State;
+
+    #[inline(always)]
+    fn deref(&self) -> &State {
+        &self.0
+    }
+}
+
+impl Drop for Lua {
+    fn drop(&mut self) {
+        unsafe { ffi::lua_close(self.0.as_ptr()) }
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lua {
+    pub fn new() -> Self {
+        let s = State::new();
+        s.open_libs();
+        s.set_top(0);
+        Self(s)
+    }
+
+    /// Wraps a `State` created by [`State::new`], which will be closed by this `Lua`
+    pub fn from_state(s: State) -> Self {
+        Self(s)
+    }
+
+    /// [-0, +1, -]
+    #[inline]
+    pub fn globals(&self) -> ValRef<'_> {
+        self.global()
+    }
+
+    /// [-0, +1, m]
+    #[inline]
+    pub fn create_table(&self) -> ValRef<'_> {
+        self.table(0, 0)
+    }
+
+    /// [-0, +1, m]
+    #[inline]
+    pub fn create_table_with_capacity(&self, narr: c_int, nrec: c_int) -> ValRef<'_> {
+        self.table(narr, nrec)
+    }
+
+    /// [-0, +1, m] Creates an array table from the values of `iter`
+    pub fn create_sequence_from<T: ToLua, I: IntoIterator<Item = T>>(&self, iter: I) -> ValRef<'_> {
+        self.push(IterVec(iter.into_iter()));
+        self.val(-1)
+    }
+
+    /// [-0, +1, m]
+    pub fn create_string(&self, s: impl AsRef<[u8]>) -> ValRef<'_> {
+        self.push_bytes(s.as_ref());
+        self.val(-1)
+    }
+
+    /// [-0, +1, m] Unlike mlua, `func` takes its arguments directly, see [`LuaFn`]
+    pub fn create_function<'a, F: LuaFn<'a, (), ARGS, RET>, ARGS: 'a, RET: 'a>(
+        &self,
+        func: F,
+    ) -> ValRef<'_> {
+        self.push(RsFn::new(func));
+        self.val(-1)
+    }
+
+    /// [-0, +1, m]
+    pub fn create_userdata<T: UserData>(&self, data: T) -> ValRef<'_> {
+        self.push(data);
+        self.val(-1)
+    }
+
+    /// [-0, +0, -] Creates a coroutine running the function `func`
+    pub fn create_thread(&self, func: &ValRef) -> Coroutine {
+        Coroutine::with_fn(self, func.index)
+    }
+
+    /// Prepares the chunk `source` to be executed or evaluated, see [`Chunk`]
+    pub fn load<'a>(&'a self, source: &'a str) -> Chunk<'a> {
+        Chunk {
+            state: self,
+            source,
+            name: None,
+        }
+    }
+
+    /// [-0, +0, e]
+    pub fn set_named_registry_value<V: ToLua>(&self, name: &str, value: V) -> Result<(), Error> {
+        self.c_reg().set(name, value);
+        Ok(())
+    }
+
+    /// [-0, +0, -] `None` if the value is absent or can't be converted to `R`
+    pub fn named_registry_value<R: for<'b> FromLua<'b>>(&self, name: &str) -> Option<R> {
+        self.c_reg().getopt(name)
+    }
+
+    /// [-0, +0, -]
+    pub fn unset_named_registry_value(&self, name: &str) {
+        self.c_reg().set(name, ());
+    }
+
+    /// Maps to `LUA_GCCOLLECT`
+    pub fn gc_collect(&self) -> Result<(), Error> {
+        self.gc(GcOption::Collect, 0);
+        Ok(())
+    }
+
+    pub fn gc_stop(&self) {
+        self.gc(GcOption::Stop, 0);
+    }
+
+    pub fn gc_restart(&self) {
+        self.gc(GcOption::Restart, 0);
+    }
+
+    pub fn gc_is_running(&self) -> bool {
+        self.gc(GcOption::IsRunning, 0) != 0
+    }
+
+    pub fn gc_gen(&self, minor_multiplier: c_int, major_multiplier: c_int) -> GcMode {
+        let params = GcParams {
+            minormul: minor_multiplier,
+            majormul: major_multiplier,
+            ..Default::default()
+        };
+        self.gc_set_mode(GcMode::Generational, params)
+    }
+
+    pub fn gc_inc(&self, pause: c_int, step_multiplier: c_int, step_size: c_int) -> GcMode {
+        let params = GcParams {
+            pause,
+            stepmul: step_multiplier,
+            stepsize: step_size,
+            ..Default::default()
+        };
+        self.gc_set_mode(GcMode::Incremental, params)
+    }
+
+    /// Memory in use by this state in bytes
+    pub fn used_memory(&self) -> usize {
+        self.memory_used()
+    }
+}
+
+/// A chunk returned by [`Lua::load`], which isn't compiled until it's executed
+pub struct Chunk<'a> {
+    state: &'a State,
+    source: &'a str,
+    name: Option<String>,
+}
+
+impl<'a> Chunk<'a> {
+    /// The name of the chunk in the error messages and tracebacks
+    pub fn set_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// [-0, +1, -] Compiles `source` with the name of this chunk
+    fn compile(&self, source: &str) -> Result<(), Error> {
+        match self.name.as_deref() {
+            Some(name) => self.state.load_named(source, name),
+            None => self.state.load_string(source),
+        }
+    }
+
+    /// Calls the compiled chunk with `args` in protected mode, and converts its results
+    fn call_compiled<A: ToLuaMulti, R: for<'b> FromLuaMulti<'b>>(
+        &self,
+        top: Index,
+        args: A,
+    ) -> Result<R, Error> {
+        let s = self.state;
+        let nargs = s.pushx(args);
+        let result = s
+            .to_error(s.pcall(nargs, R::COUNT as _, top + 1))
+            .and_then(|_| R::from_lua(s, top + 2).ok_or(Error::ConvertFailed));
+        s.set_top(top);
+        result
+    }
+
+    /// Executes the chunk, the results are discarded
+    pub fn exec(self) -> Result<(), Error> {
+        self.call(())
+    }
+
+    /// Calls the chunk with `args` as `...`
+    pub fn call<A: ToLuaMulti, R: for<'b> FromLuaMulti<'b>>(self, args: A) -> Result<R, Error> {
+        let s = self.state;
+        let top = s.get_top();
+        s.push_fn(Some(State::traceback_c));
+        if let Err(err) = self.compile(self.source) {
+            s.set_top(top);
+            return Err(err);
+        }
+        self.call_compiled(top, args)
+    }
+
+    /// Evaluates the chunk as an expression, or as statements if it's not an expression
+    pub fn eval<R: for<'b> FromLuaMulti<'b>>(self) -> Result<R, Error> {
+        let s = self.state;
+        let top = s.get_top();
+        s.push_fn(Some(State::traceback_c));
+        if self.compile(&format!("return {}", self.source)).is_err() {
+            if let Err(err) = self.compile(self.source) {
+                s.set_top(top);
+                return Err(err);
+            }
+        }
+        self.call_compiled(top, ())
+    }
+
+    /// [-0, +1, -] Compiles the chunk into a function
+    pub fn into_function(self) -> Result<ValRef<'a>, Error> {
+        self.compile(self.source)?;
+        Ok(self.state.val(-1))
+    }
+}
@@ -4,7 +4,10 @@ use crate::{ffi::*, lua_Integer as Integer, lua_Number as Number, str::*};
 
 use alloc::boxed::Box;
 use alloc::format;
+use alloc::rc::Rc;
 use alloc::sync::Arc;
+use core::any::{Any, TypeId};
+use core::cell::RefCell;
 use core::fmt::Debug;
 use core::future::Future;
 use core::marker::PhantomData;
@@ -15,6 +18,11 @@ use libc::c_int;
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct CRegRef(pub i32);
 
+/// Represents a lua string stored in the C registry by [`State::intern`],
+/// which is pushed by `lua_rawgeti` without creating and hashing the string again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InternedKey(pub CRegRef);
+
 /// Represents a referenced value in the C registry of lua
 #[derive(Debug, PartialEq, Eq)]
 pub struct CRegVal<'a> {
@@ -38,15 +46,137 @@ pub struct Strict<I>(pub I);
 /// Represents a strict typed boolean value
 pub type StrictBool = Strict<bool>;
 
+/// Represents a strict typed integer value, floats are not accepted
+pub type StrictInt<T> = Strict<T>;
+
+/// Represents an integer value which is converted from lua without losing data,
+/// floats with fractional part and values out of the range of `T` are rejected,
+/// unlike the plain integer types, which truncate them silently
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deref)]
+pub struct CheckedInt<T>(pub T);
+
+/// Shorthand of [`CheckedInt`], e.g. `Checked<u8>` rejects `300` instead of wrapping it to `44`
+pub type Checked<T> = CheckedInt<T>;
+
+/// A rust function pushed by [`State::scoped_fn`], which may capture borrowed data.
+/// It's only lent to the scope, the lua function is invalidated when the scope returns
+/// and calling it then raises an error
+#[derive(Deref)]
+pub struct ScopedFn<'a> {
+    #[deref]
+    func: ValRef<'a>,
+    cell: Reference,
+    boxed: *mut (dyn FnMut(&State) -> c_int + 'a),
+}
+
+struct ScopedCell {
+    func: Option<*mut (dyn FnMut(&State) -> c_int + 'static)>,
+    busy: bool,
+}
+
+type AppData = alloc::collections::BTreeMap<TypeId, Rc<dyn Any>>;
+
+/// How [`State::push_result_with`] reports an error to lua
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Raises the error message by `lua_error`
+    Raise,
+    /// Returns `nil, err`
+    ReturnNilErr,
+    /// Returns `false, err`
+    ReturnFalseErr,
+}
+
+/// Returns the value on `Ok` and `nil, err` on `Err` to lua without raising,
+/// the error message is formatted by `Display`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fallible<T, E>(pub Result<T, E>);
+
+impl<T, E> From<Result<T, E>> for Fallible<T, E> {
+    #[inline(always)]
+    fn from(r: Result<T, E>) -> Self {
+        Self(r)
+    }
+}
+
+/// Converted to a lua string formatted by `Display`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deref)]
+pub struct DisplayStr<T>(pub T);
+
+/// Converted from a lua string parsed by `FromStr`, the reverse of [`DisplayStr`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deref)]
+pub struct ParseStr<T>(pub T);
+
+/// A mismatched argument reported by [`State::args_checked`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArgMismatch {
+    pub index: Index,
+    pub expected: &'static str,
+    pub got: String,
+}
+
+/// All the mismatched arguments of a [`State::args_checked`] call
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArgError(pub Vec<ArgMismatch>);
+
+impl core::fmt::Display for ArgError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, m) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(
+                f,
+                "bad argument #{} ({} expected, got {})",
+                m.index, m.expected, m.got
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ArgError> for Error {
+    fn from(e: ArgError) -> Self {
+        Error::Runtime(e.to_string())
+    }
+}
+
+/// Represents an integer flag set, the unknown bits are rejected when converted from lua
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags<T>(pub T);
+
+/// Integer flag sets used by [`Flags`], implemented for `bitflags` types by [`impl_bitflags!`]
+pub trait BitFlags: Sized {
+    /// Returns `None` if any unknown bit is set
+    fn from_bits(bits: lua_Integer) -> Option<Self>;
+    fn bits(&self) -> lua_Integer;
+}
+
 /// Represents an iterator will be converted to a lua array table
 pub struct IterVec<T: ToLua, I: Iterator<Item = T>>(pub I);
 
 /// Represents an iterator will be converted to a lua table
 pub struct IterMap<K: ToLua, V: ToLua, I: Iterator<Item = (K, V)>>(pub I);
 
+/// Represents a rust `Vec` which can be mutated in place by lua as an array userdata
+#[derive(Default, Deref, DerefMut)]
+pub struct VecHandle<T>(pub Vec<T>);
+
+/// Represents a shared byte buffer exposed to lua without copying it into a lua string
+#[derive(Clone, Deref)]
+pub struct ByteView(pub Arc<[u8]>);
+
 /// Represents an iterator
 pub struct BoxIter<'a, T>(pub Box<dyn Iterator<Item = T> + 'a>);
 
+/// Represents a fallible iterator, the error is reported to lua by the [`ErrorMode`], which raises by default.
+/// Only [`ErrorMode::Raise`] stops a generic `for` loop with the error, `nil, err` ends the loop silently
+/// like the end of the iteration, and `false, err` is iterated as a value, the iterator may be resumed after both
+pub struct BoxResultIter<'a, T, E> {
+    pub iter: Box<dyn Iterator<Item = Result<T, E>> + 'a>,
+    pub mode: ErrorMode,
+}
+
 /// Represents a function will be wrapped as a lua C function
 pub struct RsFn<THIS, T, O, F>(pub F, PhantomData<(THIS, T, O)>);
 
@@ -117,8 +247,15 @@ pub trait UserData: Sized {
     /// set the `__len` metamethod, if true, return the size of this userdata
     const RAW_LEN: bool = false;
 
+    /// set the `__close` metamethod, if true, call [`UserData::close`] when a `<close>` variable goes out of scope
+    const CLOSE: bool = false;
+
     const IS_POINTER: bool = false;
 
+    /// set the `__metatable` field to [`UserData::TYPE_NAME`], so `getmetatable` in lua returns the
+    /// name rather than the metatable, and `setmetatable` raises an error
+    const PROTECT_METATABLE: bool = false;
+
     const WEAK_REF_CACHE: bool = true;
 
     /// add methods
@@ -130,6 +267,14 @@ pub trait UserData: Sized {
     /// add fields setter
     fn setter(fields: &ValRef) {}
 
+    /// release the resource held by this userdata, see [`UserData::CLOSE`]
+    fn close(&mut self, s: &State) {}
+
+    /// dynamic field getter, called by `__index` when no getter, method or uservalue field matched
+    fn index_fallback(&self, s: &State, key: ValRef) -> Option<Pushed> {
+        None
+    }
+
     fn init_metatable(mt: &ValRef) {
         mt.setf(cstr!("__name"), Self::TYPE_NAME);
         mt.setf(cstr!("__gc"), Self::__gc as CFunction);
@@ -138,6 +283,14 @@ pub trait UserData: Sized {
             mt.setf(cstr!("__len"), Self::__len as CFunction);
         }
 
+        if Self::CLOSE {
+            mt.setf(cstr!("__close"), Self::__close as CFunction);
+        }
+
+        if Self::PROTECT_METATABLE {
+            mt.setf(cstr!("__metatable"), Self::TYPE_NAME);
+        }
+
         {
             let getter = &mt.state.table(0, 0);
             Self::getter(getter);
@@ -243,6 +396,13 @@ pub trait UserData: Sized {
             }
         }
 
+        // access the rust fallback
+        if let Some(this) = <&Self as FromLua>::from_lua(&s, 1) {
+            if let Some(Pushed(n)) = this.index_fallback(&s, s.val(2)) {
+                return n;
+            }
+        }
+
         // access getter function
         if let Some(getter) = Self::INDEX_GETTER {
             s.push(getter);
@@ -298,6 +458,12 @@ pub trait UserData: Sized {
         1
     }
 
+    unsafe extern "C" fn __close(l: *mut lua_State) -> c_int {
+        let s = State::from_ptr(l);
+        <&mut Self>::check(&s, 1).close(&s);
+        0
+    }
+
     unsafe extern "C" fn __tostring(l: *mut lua_State) -> c_int
     where
         Self: ToString,
@@ -306,6 +472,20 @@ pub trait UserData: Sized {
     }
 }
 
+// [-0, +1] Pushes a new userdata without looking up the cache
+fn push_userdata_uncached<T: UserData>(s: &State, val: T) {
+    if T::IS_POINTER {
+        s.push_userdata_pointer_body(val, T::init_metatable);
+    } else {
+        let count = val.uservalue_count(s);
+        s.push_userdatauv(val, count);
+        s.set_or_init_metatable(T::init_metatable);
+    }
+    if T::INDEX_USERVALUE {
+        s.balance_with(T::init_userdata);
+    }
+}
+
 impl<T: UserData> ToLua for T {
     #[inline(always)]
     fn to_lua(self, s: &State) {
@@ -314,16 +494,7 @@ impl<T: UserData> ToLua for T {
             return;
         }
 
-        if T::IS_POINTER {
-            s.push_userdata_pointer_body(self, Self::init_metatable);
-        } else {
-            let count = self.uservalue_count(s);
-            s.push_userdatauv(self, count);
-            s.set_or_init_metatable(Self::init_metatable);
-        }
-        if T::INDEX_USERVALUE {
-            s.balance_with(T::init_userdata);
-        }
+        push_userdata_uncached(s, self);
 
         if !key.is_null() {
             T::cache_userdata(s, key)
@@ -366,6 +537,15 @@ impl ToLua for serde_bytes::ByteBuf {
     }
 }
 
+/// Implemented by the rust functions which can be called from lua.
+///
+/// The arguments of an async function must be owned, since the borrowed arguments would dangle
+/// after the coroutine yields:
+///
+/// ```compile_fail
+/// let s = llua::State::new();
+/// s.global().register("f", |a: &'static str| async move { a.len() });
+/// ```
 pub trait LuaFn<'a, THIS: 'a, ARGS: 'a, RET: 'a> {
     unsafe extern "C" fn wrapper(l: *mut lua_State) -> c_int;
 }
@@ -487,6 +667,8 @@ impl<T> ToLua for UserDataWrapper<T> {
 impl<T: ToLua, I: Iterator<Item = T>> ToLua for IterVec<T, I> {
     #[inline(always)]
     fn to_lua(self, s: &State) {
+        // elements are stored into the table one by one, only the table and an element take slots
+        s.check_stack_msg(2, "IterVec");
         let r = s.table(self.0.size_hint().1.unwrap_or(0) as _, 0);
         let mut i = 1;
         for e in self.0.into_iter() {
@@ -496,16 +678,127 @@ impl<T: ToLua, I: Iterator<Item = T>> ToLua for IterVec<T, I> {
     }
 }
 
+/// Converted to a map table `{elem = true}`
+#[cfg(feature = "std")]
+impl<T: ToLua, H> ToLua for std::collections::HashSet<T, H> {
+    fn to_lua(self, s: &State) {
+        s.check_stack_msg(3, "HashSet");
+        let r = s.table(0, self.len() as _);
+        for e in self {
+            r.set(e, true);
+        }
+    }
+}
+
+/// Checks the value at `i` can be a table key, nil and non-finite numbers are rejected,
+/// lua raises an error for nil and NaN keys, and infinite keys can't be represented by most formats
+pub(crate) fn check_table_key(s: &State, i: Index) -> Result<(), String> {
+    match s.type_of(i) {
+        Type::Nil => Err("invalid table key: nil".into()),
+        Type::Number if !s.is_integer(i) && !s.to_number(i).is_finite() => {
+            Err(format!("invalid table key: {}", s.to_number(i)))
+        }
+        _ => Ok(()),
+    }
+}
+
 impl<K: ToLua, V: ToLua, I: Iterator<Item = (K, V)>> ToLua for IterMap<K, V, I> {
     #[inline(always)]
     fn to_lua(self, s: &State) {
+        s.check_stack_msg(3, "IterMap");
         let r = s.table(0, self.0.size_hint().1.unwrap_or(0) as _);
+        // the error is raised after the loop, so the iterator and its remaining items are dropped
+        // before `lua_error` longjmps over this frame
+        let mut result = Ok(());
         for (k, v) in self.0 {
-            r.set(k, v);
+            s.push(k);
+            result = check_table_key(s, -1);
+            if result.is_err() {
+                break;
+            }
+            s.push(v);
+            s.set_table(r.index);
+        }
+        if let Err(err) = result {
+            s.error_string(err);
         }
     }
 }
 
+impl<T: ToLua + Clone + for<'a> FromLua<'a> + 'static> UserData for VecHandle<T> {
+    fn methods(mt: &ValRef) {
+        mt.register("__len", |this: &Self| this.0.len());
+        mt.register("__index", |s: &State, this: &Self| {
+            if s.is_integer(2) {
+                let i = s.to_integer(2);
+                let elem = if i > 0 {
+                    this.0.get(i as usize - 1)
+                } else {
+                    None
+                };
+                s.pushed(elem.cloned())
+            } else {
+                s.get_metatable_by(1, StackRef(2));
+                Pushed(1)
+            }
+        });
+        mt.register(
+            "__newindex",
+            |s: &State, this: &mut Self, i: Integer, v: T| {
+                let len = this.0.len() as Integer;
+                if i > 0 && i <= len {
+                    this.0[i as usize - 1] = v;
+                } else if i == len + 1 {
+                    this.0.push(v);
+                } else {
+                    // `lua_error` longjmps over this frame, drop the owned value first
+                    drop(v);
+                    s.error_string(format!("index {i} out of range [1, {}]", len + 1));
+                }
+            },
+        );
+        mt.register("push", |this: &mut Self, v: T| this.0.push(v));
+        mt.register("pop", |this: &mut Self| this.0.pop());
+    }
+}
+
+impl<T> From<Vec<T>> for VecHandle<T> {
+    fn from(v: Vec<T>) -> Self {
+        Self(v)
+    }
+}
+
+impl UserData for ByteView {
+    const TYPE_NAME: &'static str = "ByteView";
+
+    fn methods(mt: &ValRef) {
+        mt.register("__len", |this: &Self| this.0.len());
+        mt.register("__index", |s: &State, this: &Self| {
+            if s.is_integer(2) {
+                let i = s.to_integer(2);
+                let byte = if i > 0 {
+                    this.0.get(i as usize - 1)
+                } else {
+                    None
+                };
+                s.pushed(byte.copied())
+            } else {
+                s.get_metatable_by(1, StackRef(2));
+                Pushed(1)
+            }
+        });
+        mt.register("to_string", |s: &State, this: &Self| {
+            s.pushed(this.0.as_ref())
+        });
+    }
+}
+
+impl<T: Into<Arc<[u8]>>> From<T> for ByteView {
+    fn from(bytes: T) -> Self {
+        Self(bytes.into())
+    }
+}
+
 impl<'a, T: ToLuaMulti> BoxIter<'a, T> {
     pub fn new(iter: impl Iterator<Item = T> + 'a) -> Self {
         Self(Box::new(iter))
@@ -523,6 +816,51 @@ impl<'a, T: ToLuaMulti> BoxIter<'a, T> {
     }
 }
 
+impl<'a, T: ToLuaMulti, E: core::fmt::Display> BoxResultIter<'a, T, E> {
+    pub fn new(iter: impl Iterator<Item = Result<T, E>> + 'a) -> Self {
+        Self::with_mode(iter, ErrorMode::Raise)
+    }
+
+    pub fn with_mode(iter: impl Iterator<Item = Result<T, E>> + 'a, mode: ErrorMode) -> Self {
+        Self {
+            iter: Box::new(iter),
+            mode,
+        }
+    }
+
+    unsafe extern "C" fn lua_fn(l: *mut lua_State) -> c_int {
+        let s = State::from_ptr(l);
+        let p = s.to_userdata(ffi::lua_upvalueindex(1));
+        let this: &mut Self = mem::transmute(p);
+        match this.iter.next() {
+            Some(Ok(v)) => s.pushx(v),
+            Some(Err(e)) => {
+                let msg = e.to_string();
+                drop(e);
+                match this.mode {
+                    ErrorMode::Raise => s.error_string(msg),
+                    ErrorMode::ReturnNilErr => s.push_nil(),
+                    ErrorMode::ReturnFalseErr => s.push(false),
+                }
+                s.push_string(&msg);
+                2
+            }
+            None => 0,
+        }
+    }
+}
+
+impl<'a, T: ToLuaMulti, E: core::fmt::Display> ToLua for BoxResultIter<'a, T, E> {
+    #[inline(always)]
+    fn to_lua(self, s: &State) {
+        s.push_userdata(self, None);
+        let mt = s.table(0, 1);
+        mt.set("__gc", __gc::<Self> as CFunction);
+        s.set_metatable(-2);
+        s.push_cclosure(Some(Self::lua_fn), 1);
+    }
+}
+
 unsafe extern "C" fn __gc<T>(l: *mut lua_State) -> i32 {
     let s = State::from_ptr(l);
     s.to_userdata_typed::<T>(1)
@@ -530,6 +868,24 @@ unsafe extern "C" fn __gc<T>(l: *mut lua_State) -> i32 {
     return 0;
 }
 
+impl Drop for ScopedFn<'_> {
+    fn drop(&mut self) {
+        let s = self.func.state;
+        s.raw_geti(LUA_REGISTRYINDEX, self.cell.value() as _);
+        let cell = unsafe { s.to_userdata_typed::<ScopedCell>(-1) };
+        s.pop(1);
+        s.unreference(LUA_REGISTRYINDEX, self.cell);
+        if let Some(cell) = cell {
+            cell.func = None;
+            // still running, leak the closure rather than freeing it in use
+            if cell.busy {
+                return;
+            }
+        }
+        drop(unsafe { Box::from_raw(self.boxed) });
+    }
+}
+
 impl<'a, T: ToLuaMulti> ToLua for BoxIter<'a, T> {
     #[inline(always)]
     fn to_lua(self, s: &State) {
@@ -560,12 +916,12 @@ impl<'a, THIS: 'a, T: 'a, O: 'a, F: LuaFn<'a, THIS, T, O>> ToLua for RsFn<THIS,
     }
 }
 
-impl ToLua for fn(State) -> i32 {
+impl ToLua for fn(StateRef) -> i32 {
     fn to_lua(self, s: &State) {
         unsafe extern "C" fn wrapper(l: *mut lua_State) -> c_int {
             let state = State::from_ptr(l);
             let fp = state.to_pointer(lua_upvalueindex(1));
-            let fp: fn(State) -> c_int = mem::transmute(fp);
+            let fp: fn(StateRef) -> c_int = mem::transmute(fp);
             fp(state)
         }
 
@@ -588,6 +944,13 @@ impl ToLua for CRegRef {
     }
 }
 
+impl ToLua for InternedKey {
+    #[inline(always)]
+    fn to_lua(self, s: &State) {
+        s.raw_geti(LUA_REGISTRYINDEX, (self.0).0 as _);
+    }
+}
+
 impl ToLua for CRegVal<'_> {
     #[inline(always)]
     fn to_lua(self, s: &State) {
@@ -671,6 +1034,38 @@ pub trait FromLua<'a>: Sized + 'a {
     }
 }
 
+impl<T: core::fmt::Display> ToLua for DisplayStr<T> {
+    #[inline(always)]
+    fn to_lua(self, s: &State) {
+        s.push(self.0.to_string());
+    }
+}
+
+impl<T: core::str::FromStr + 'static> FromLua<'_> for ParseStr<T> {
+    #[inline(always)]
+    fn from_lua(s: &State, i: Index) -> Option<Self> {
+        s.to_str_noconv(i)?.parse().ok().map(Self)
+    }
+}
+
+impl<T: BitFlags + 'static> FromLua<'_> for Flags<T> {
+    #[inline(always)]
+    fn from_lua(s: &State, i: Index) -> Option<Self> {
+        if s.is_integer(i) {
+            T::from_bits(s.to_integer(i)).map(Self)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: BitFlags> ToLua for Flags<T> {
+    #[inline(always)]
+    fn to_lua(self, s: &State) {
+        s.push_integer(self.0.bits());
+    }
+}
+
 impl FromLua<'_> for AnyVal {
     #[inline(always)]
     fn from_lua(_s: &State, _i: Index) -> Option<AnyVal> {
@@ -696,6 +1091,28 @@ impl<'a> FromLua<'a> for &'a str {
     }
 }
 
+/// Reads the array part of a table, until the first nil element,
+/// `Vec<u8>` is specialized to read the bytes of a string instead
+impl<'a, T: FromLua<'a>> FromLua<'a> for Vec<T> {
+    default fn from_lua(s: &'a State, i: Index) -> Option<Self> {
+        if s.type_of(i) != Type::Table || !s.check_stack(1) {
+            return None;
+        }
+        let i = s.abs_index(i);
+        let _balance = BalanceState::new(s);
+        let len = s.raw_len(i);
+        let mut result = Vec::with_capacity(len);
+        for n in 1..=len as lua_Integer {
+            if s.raw_geti(i, n) == Type::Nil {
+                break;
+            }
+            result.push(T::from_lua(s, -1)?);
+            s.pop(1);
+        }
+        Some(result)
+    }
+}
+
 impl FromLua<'_> for Vec<u8> {
     #[inline(always)]
     fn from_lua(s: &State, i: Index) -> Option<Vec<u8>> {
@@ -703,6 +1120,51 @@ impl FromLua<'_> for Vec<u8> {
     }
 }
 
+/// Accepts an array table `{"read", "write"}`, whose elements are the members,
+/// or a map table `{read = true}`, whose keys with truthy values are the members.
+/// A table whose first element is a boolean is a map, e.g. `{[1] = true, [2] = true}`
+/// converted from a set of integers, so a set of booleans can't be given as an array
+#[cfg(feature = "std")]
+impl<'a, T, H> FromLua<'a> for std::collections::HashSet<T, H>
+where
+    T: FromLua<'a> + Eq + core::hash::Hash,
+    H: core::hash::BuildHasher + Default + 'a,
+{
+    fn from_lua(s: &'a State, i: Index) -> Option<Self> {
+        if s.type_of(i) != Type::Table || !s.check_stack(3) {
+            return None;
+        }
+        let i = s.abs_index(i);
+        let _balance = BalanceState::new(s);
+        let mut result = Self::default();
+        let len = s.raw_len(i);
+        let is_array = len > 0 && {
+            let first = s.raw_geti(i, 1);
+            s.pop(1);
+            first != Type::Boolean
+        };
+        if is_array {
+            for n in 1..=len as lua_Integer {
+                s.raw_geti(i, n);
+                result.insert(T::from_lua(s, -1)?);
+                s.pop(1);
+            }
+        } else {
+            s.push_nil();
+            while s.next(i) {
+                if s.to_bool(-1) {
+                    // convert a copy, `next` is confused if the key is converted in place
+                    s.push_value(-2);
+                    result.insert(T::from_lua(s, -1)?);
+                    s.pop(1);
+                }
+                s.pop(1);
+            }
+        }
+        Some(result)
+    }
+}
+
 impl<'a> FromLua<'a> for Value<'a> {
     #[inline(always)]
     fn from_lua(s: &'a State, i: Index) -> Option<Value<'a>> {
@@ -726,6 +1188,11 @@ impl<'a> FromLua<'a> for &'a [u8] {
     #[inline(always)]
     fn from_lua(s: &State, i: Index) -> Option<&'a [u8]> {
         let s: &'a State = unsafe { core::mem::transmute(s) };
+        if s.type_of(i) == Type::Userdata {
+            if let Some(view) = <&ByteView as FromLua>::from_lua(s, i) {
+                return Some(view.0.as_ref());
+            }
+        }
         s.to_bytes(i).or_else(|| unsafe {
             let p = s.to_userdata(i);
             if p.is_null() {
@@ -750,6 +1217,32 @@ impl<T: UserData + Clone> FromLua<'_> for ClonedUserData<T> {
     }
 }
 
+/// Gets the `Rc` held by the userdata, which shares the instance with lua
+impl<T: 'static> FromLua<'_> for Rc<T>
+where
+    Rc<T>: UserData,
+{
+    const TYPE_NAME: &'static str = <Rc<T> as UserData>::TYPE_NAME;
+
+    #[inline(always)]
+    fn from_lua(s: &State, i: Index) -> Option<Self> {
+        <&Rc<T> as FromLua<'_>>::from_lua(s, i).cloned()
+    }
+}
+
+/// Gets the `Arc` held by the userdata, which shares the instance with lua
+impl<T: 'static> FromLua<'_> for Arc<T>
+where
+    Arc<T>: UserData,
+{
+    const TYPE_NAME: &'static str = <Arc<T> as UserData>::TYPE_NAME;
+
+    #[inline(always)]
+    fn from_lua(s: &State, i: Index) -> Option<Self> {
+        <&Arc<T> as FromLua<'_>>::from_lua(s, i).cloned()
+    }
+}
+
 impl<'a, T: UserData> FromLua<'a> for &'a T {
     const TYPE_NAME: &'static str = T::TYPE_NAME;
 
@@ -819,6 +1312,7 @@ impl<'a, T: FromLua<'a>> FromLua<'a> for Option<T> {
     }
 }
 
+// the float values are truncated by `as`, use `CheckedInt` to reject them
 macro_rules! impl_integer {
     ($($t:ty) *) => {
         $(
@@ -852,12 +1346,49 @@ macro_rules! impl_integer {
                 }
             }
         }
+
+        impl FromLua<'_> for CheckedInt<$t> {
+            #[inline(always)]
+            fn from_lua(s: &State, i: Index) -> Option<CheckedInt<$t>> {
+                if s.type_of(i) == Type::Number {
+                    <$t>::try_from(s.to_integerx(i)?).ok().map(Self)
+                } else {
+                    None
+                }
+            }
+        }
         )*
     }
 }
 
 impl_integer!(isize usize u8 u16 u32 u64 i8 i16 i32 Integer);
 
+// zero and the values out of the range of the inner type are rejected, instead of being truncated
+macro_rules! impl_nonzero {
+    ($($t:ident($i:ty)) *) => {
+        $(
+        impl ToLua for core::num::$t {
+            #[inline(always)]
+            fn to_lua(self, s: &State) {
+                s.push(self.get());
+            }
+        }
+
+        impl FromLua<'_> for core::num::$t {
+            #[inline(always)]
+            fn from_lua(s: &State, i: Index) -> Option<core::num::$t> {
+                core::num::$t::new(s.arg::<CheckedInt<$i>>(i)?.0)
+            }
+        }
+        )*
+    }
+}
+
+impl_nonzero!(
+    NonZeroU8(u8) NonZeroU16(u16) NonZeroU32(u32) NonZeroU64(u64) NonZeroUsize(usize)
+    NonZeroI8(i8) NonZeroI16(i16) NonZeroI32(i32) NonZeroI64(i64) NonZeroIsize(isize)
+);
+
 pub trait ToLuaMulti: Sized {
     fn to_lua(self, _s: &State) -> c_int;
 
@@ -875,6 +1406,11 @@ pub trait FromLuaMulti<'a>: Sized {
     fn from_lua(_s: &'a State, _begin: Index) -> Option<Self> {
         None
     }
+
+    /// Like `from_lua`, but keeps converting after a mismatch and records every mismatched argument
+    fn from_lua_checked(s: &'a State, begin: Index, err: &mut ArgError) -> Option<Self> {
+        Self::from_lua(s, begin)
+    }
 }
 
 impl FromLuaMulti<'_> for () {
@@ -939,6 +1475,18 @@ impl<'a, T: FromLua<'a>> FromLuaMulti<'a> for T {
     fn from_lua(s: &'a State, begin: Index) -> Option<Self> {
         T::from_lua(s, begin)
     }
+
+    fn from_lua_checked(s: &'a State, begin: Index, err: &mut ArgError) -> Option<Self> {
+        let result = T::from_lua(s, begin);
+        if result.is_none() {
+            err.0.push(ArgMismatch {
+                index: begin,
+                expected: T::TYPE_NAME,
+                got: s.typename_at(begin).into_owned(),
+            });
+        }
+        result
+    }
 }
 
 impl<T: ToLuaMulti, E: Debug + 'static> ToLuaMulti for Result<T, E> {
@@ -956,6 +1504,76 @@ impl<T: ToLuaMulti, E: Debug + 'static> ToLuaMulti for Result<T, E> {
     }
 }
 
+impl<T: ToLuaMulti, E: core::fmt::Display> ToLuaMulti for Fallible<T, E> {
+    #[inline(always)]
+    fn to_lua(self, s: &State) -> c_int {
+        match self.0 {
+            Ok(val) => val.to_lua(s),
+            Err(e) => {
+                s.push_nil();
+                s.push_string(&e.to_string());
+                2
+            }
+        }
+    }
+}
+
+/// Represents a rust error raised to lua as a table, which has a `message` field
+/// formatted by `Display` and a `cause` field holding the error's source recursively
+#[cfg(feature = "std")]
+pub struct ErrorChain<E>(pub E);
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error> From<E> for ErrorChain<E> {
+    #[inline(always)]
+    fn from(e: E) -> Self {
+        Self(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl State {
+    /// [-0, +1, -] Pushes an error and its source chain as a table with `message` and `cause` fields
+    pub fn push_error_chain(&self, e: &dyn std::error::Error) {
+        fn init_metatable(mt: &ValRef) {
+            unsafe extern "C" fn tostring(l: *mut lua_State) -> c_int {
+                let s = State::from_ptr(l);
+                s.get_field(1, cstr!("message"));
+                1
+            }
+            mt.setf(cstr!("__tostring"), tostring as CFunction);
+        }
+
+        let t = self.table(0, 2);
+        t.set("message", e.to_string());
+        if let Some(cause) = e.source() {
+            self.push_error_chain(cause);
+            t.set("cause", TopVal);
+        }
+        self.set_or_init_metatable(init_metatable);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ToLuaMulti, E: std::error::Error> ToLuaMulti for Result<T, ErrorChain<E>> {
+    #[inline(always)]
+    fn to_lua(self, s: &State) -> c_int {
+        match self {
+            Ok(val) => val.to_lua(s),
+            Err(e) => {
+                s.push_error_chain(&e.0);
+                s.error()
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn to_lua_result(self, s: &State) -> Result<c_int, Error> {
+        self.map(|val| val.to_lua(s))
+            .map_err(|e| Error::runtime(e.0.to_string()))
+    }
+}
+
 macro_rules! replace_expr {
     ($_t:tt $sub:expr) => {
         $sub
@@ -996,11 +1614,13 @@ macro_rules! impl_luafn {
             }
         }
 
-        // For async function
-        impl<'a, FN: Fn($($x,)*)->RETF + 'a, $($x: FromLua<'a>,)* RET: ToLuaMulti + 'a, RETF: Future<Output = RET> + 'a> LuaFn<'a, (), ($($x,)*), RetFuture<RET, RETF>> for FN {
+        // For async function, the args must be owned, since the stack may be changed after yielding,
+        // which makes the borrowed args such as `&str` dangling. `for<'b>` rejects them,
+        // `&'static str` only implements `FromLua<'static>`
+        impl<'a, FN: Fn($($x,)*)->RETF + 'a, $($x: for<'b> FromLua<'b> + 'static,)* RET: ToLuaMulti + 'a, RETF: Future<Output = RET> + 'a> LuaFn<'a, (), ($($x,)*), RetFuture<RET, RETF>> for FN {
             unsafe extern "C" fn wrapper(l: *mut lua_State) -> c_int {
                 wrapper_init!(s, l, f);
-                s.yield_task(f($($x::check(s, 1 + $i),)*))
+                s.yield_task(f($(<$x as FromLua<'a>>::check(s, 1 + $i),)*))
             }
         }
 
@@ -1012,11 +1632,12 @@ macro_rules! impl_luafn {
             }
         }
 
-        // For async function which arg0 is State
-        impl<'a, FN: Fn(State, $($x,)*)->RETF + 'a, $($x: FromLua<'a>,)* RET: ToLuaMulti + 'a, RETF: Future<Output = RET> + 'a> LuaFn<'a, (), (State, $($x,)*), RetFuture<RET, RETF>> for FN {
+        // For async function which arg0 is StateRef, the args must be owned as well
+        impl<'a, FN: Fn(StateRef<'static>, $($x,)*)->RETF + 'a, $($x: for<'b> FromLua<'b> + 'static,)* RET: ToLuaMulti + 'a, RETF: Future<Output = RET> + 'a> LuaFn<'a, (), (State, $($x,)*), RetFuture<RET, RETF>> for FN {
             unsafe extern "C" fn wrapper(l: *mut lua_State) -> c_int {
                 wrapper_init!(s, l, f);
-                s.yield_task(f(s.copy_state(), $($x::check(s, 1 + $i),)*))
+                // the future may outlive this call, so it gets an unbounded StateRef, which can't close the state
+                s.yield_task(f(State::from_ptr(s.as_ptr()), $(<$x as FromLua<'a>>::check(s, 1 + $i),)*))
             }
         }
 
@@ -1077,6 +1698,11 @@ macro_rules! impl_tuple {
             fn from_lua(s: &'a State, begin: Index) -> Option<Self> {
                 Some(( $($x::from_lua(s, begin + $i)?,)* ))
             }
+
+            fn from_lua_checked(s: &'a State, begin: Index, err: &mut ArgError) -> Option<Self> {
+                let args = ($(<$x as FromLuaMulti>::from_lua_checked(s, begin + $i, err),)*);
+                Some(( $(args.$i?,)* ))
+            }
         }
 
         impl_luafn!($(($x, $i))+);
@@ -1103,16 +1729,336 @@ impl State {
         T::from_lua(self, index)
     }
 
+    /// Raises a type error naming the first mismatched argument if the conversion failed
     #[inline(always)]
     pub fn args<'a, T: FromLuaMulti<'a>>(&'a self, index: Index) -> T {
-        if let Some(args) = T::from_lua(self, index) {
-            args
+        let mut err = ArgError::default();
+        if let Some(args) = T::from_lua_checked(self, index, &mut err) {
+            return args;
+        }
+        let first = err.0.first().map(|m| (m.index, m.expected));
+        // the error raised by lua doesn't unwind the rust frames
+        drop(err);
+        if let Some((i, expected)) = first {
+            let tname = CString::new(expected).unwrap_or_default();
+            self.type_error(i, &tname);
         } else {
             self.push_string("args not match");
             self.error();
         }
     }
 
+    /// [-0, +1, m] Pushes the userdata cached by `key`, or creates one by `make` and caches it,
+    /// like [`UserData::key_to_cache`] but the key is given by the caller, the cache is per type `T`
+    pub fn intern_userdata<T: UserData>(&self, key: *const (), make: impl FnOnce() -> T) -> ValRef {
+        if key.is_null() {
+            push_userdata_uncached(self, make());
+        } else if !T::get_cahced(self, key) {
+            push_userdata_uncached(self, make());
+            T::cache_userdata(self, key);
+        }
+        self.val(-1)
+    }
+
+    /// Converts all the arguments, the error lists every mismatched argument instead of only the first one
+    pub fn args_checked<'a, T: FromLuaMulti<'a>>(&'a self) -> Result<T, ArgError> {
+        let mut err = ArgError::default();
+        match T::from_lua_checked(self, 1, &mut err) {
+            Some(args) => Ok(args),
+            None => Err(err),
+        }
+    }
+
+    /// [-0, +1, -] Pushes the bytes as a [`ByteView`] userdata, which doesn't copy them into a lua string
+    #[inline(always)]
+    pub fn push_bytes_zero_copy(&self, bytes: impl Into<Arc<[u8]>>) {
+        self.push(ByteView(bytes.into()));
+    }
+
+    /// [-0, +1, m] Pushes a rust function which may borrow local data and calls `scope` with it,
+    /// the function is invalidated when `scope` returns, like the threads of `std::thread::scope`.
+    /// Reentrant calls are rejected, and the function can't be called again after raising an error
+    pub fn scoped_fn<'f, R>(
+        &self,
+        f: impl FnMut(&State) -> c_int + 'f,
+        scope: impl FnOnce(&ScopedFn) -> R,
+    ) -> R {
+        unsafe extern "C" fn wrapper(l: *mut lua_State) -> c_int {
+            let s = State::from_ptr(l);
+            let cell = s
+                .to_userdata_typed::<ScopedCell>(lua_upvalueindex(1))
+                .unwrap();
+            let func = match cell.func {
+                Some(_) if cell.busy => s.error_string("scoped function is already running"),
+                Some(func) => func,
+                None => s.error_string("scoped function is expired"),
+            };
+            cell.busy = true;
+            let result = (*func)(&s);
+            cell.busy = false;
+            result
+        }
+
+        let boxed: *mut (dyn FnMut(&State) -> c_int + 'f) = Box::into_raw(Box::new(f));
+        self.push_userdatauv(
+            ScopedCell {
+                func: Some(unsafe { mem::transmute(boxed) }),
+                busy: false,
+            },
+            0,
+        );
+        self.push_value(-1);
+        let cell = self.reference(LUA_REGISTRYINDEX);
+        self.push_cclosure(Some(wrapper), 1);
+        // the guard is never given away, so it can't be leaked to skip the invalidation
+        let guard = ScopedFn {
+            func: self.val(-1),
+            cell,
+            boxed,
+        };
+        scope(&guard)
+    }
+
+    /// Stores the string in the C registry, which lives until [`State::unintern`] or the state is closed
+    pub fn intern(&self, s: &str) -> InternedKey {
+        self.push_string(s);
+        InternedKey(CRegRef(self.reference(LUA_REGISTRYINDEX).value()))
+    }
+
+    #[inline(always)]
+    pub fn unintern(&self, key: InternedKey) {
+        self.unreference(LUA_REGISTRYINDEX, key.0.into());
+    }
+
+    /// [-0, +0, -] Converts the `n`-th user value of the userdata at `ud`,
+    /// returns `None` if the userdata doesn't have that value
+    pub fn uservalue_get<'a, T: FromLua<'a>>(&'a self, ud: Index, n: i32) -> Option<T> {
+        let ud = self.abs_index(ud);
+        let result = match self.get_iuservalue(ud, n) {
+            Type::None | Type::Invalid => None,
+            _ => T::from_lua(self, -1),
+        };
+        self.pop(1);
+        result
+    }
+
+    /// [-0, +0, -] Sets the `n`-th user value of the userdata at `ud`,
+    /// returns false if the userdata doesn't have that value
+    pub fn uservalue_set<V: ToLua>(&self, ud: Index, n: i32, v: V) -> bool {
+        let ud = self.abs_index(ud);
+        self.push(v);
+        // the value is popped even if failed
+        unsafe { lua_setiuservalue(self.as_ptr(), ud, n) != 0 }
+    }
+
+    /// Attaches the embedder context of type `T` to this state, replacing the previous one,
+    /// it's stored in the registry and dropped when the state is closed and no [`State::app_data`] holds it
+    pub fn set_app_data<T: 'static>(&self, data: T) {
+        let data: Rc<dyn Any> = Rc::new(RefCell::new(data));
+        unsafe { &mut *self.app_data_map() }.insert(TypeId::of::<T>(), data);
+    }
+
+    /// Gets the embedder context of type `T` set by [`State::set_app_data`], it's shared
+    /// rather than borrowed, so replacing it doesn't invalidate the one already got
+    pub fn app_data<T: 'static>(&self) -> Option<Rc<RefCell<T>>> {
+        unsafe { &*self.app_data_map() }
+            .get(&TypeId::of::<T>())?
+            .clone()
+            .downcast()
+            .ok()
+    }
+
+    /// The map of the app data, which lives in a userdata referenced by the registry
+    fn app_data_map(&self) -> *mut AppData {
+        static KEY: u8 = 0;
+        if self.raw_getp(LUA_REGISTRYINDEX, &KEY) != Type::Userdata {
+            self.pop(1);
+            self.push_userdatauv(AppData::new(), 0);
+            let mt = self.table(0, 1);
+            mt.set("__gc", __gc::<AppData> as CFunction);
+            self.set_metatable(-2);
+            self.push_value(-1);
+            self.raw_setp(LUA_REGISTRYINDEX, &KEY);
+        }
+        let map = self.to_userdata(-1) as *mut AppData;
+        self.pop(1);
+        map
+    }
+
+    /// [-0, +1, m] Pushes the static string `s`, which is created once and then reused from a registry
+    /// table keyed by its address, a string sharing the address but not the length replaces the cached one
+    pub fn push_static_str(&self, s: &'static str) {
+        static KEY: u8 = 0;
+        if self.raw_getp(LUA_REGISTRYINDEX, &KEY) != Type::Table {
+            self.pop(1);
+            self.create_table(0, 8);
+            self.push_value(-1);
+            self.raw_setp(LUA_REGISTRYINDEX, &KEY);
+        }
+        if self.raw_getp(-1, s.as_ptr()) != Type::String || self.raw_len(-1) != s.len() {
+            self.pop(1);
+            self.push(s);
+            self.push_value(-1);
+            self.raw_setp(-3, s.as_ptr());
+        }
+        self.remove(-2);
+    }
+
+    /// Installs `f` as the panic function by [`State::at_panic`], which is called with the error
+    /// message at the top of the stack when an error isn't caught by any protected call.
+    /// The closure is stored in the registry of this state
+    pub fn set_panic_fn(&self, f: impl Fn(&State) -> ! + 'static) {
+        type PanicFn = Box<dyn Fn(&State) -> !>;
+        static KEY: u8 = 0;
+
+        unsafe extern "C" fn panic(l: *mut lua_State) -> c_int {
+            let s = State::from_ptr(l);
+            if s.check_stack(1) && s.raw_getp(LUA_REGISTRYINDEX, &KEY) == Type::Userdata {
+                let f = s
+                    .to_userdata_typed::<PanicFn>(-1)
+                    .map(|f| f as *const PanicFn);
+                // the closure is still referenced by the registry
+                s.pop(1);
+                if let Some(f) = f {
+                    (*f)(&s)
+                }
+            }
+            0
+        }
+
+        let _balance = BalanceState::new(self);
+        self.push_userdatauv::<PanicFn>(Box::new(f), 0);
+        let mt = self.table(0, 1);
+        mt.set("__gc", __gc::<PanicFn> as CFunction);
+        self.set_metatable(-2);
+        self.raw_setp(LUA_REGISTRYINDEX, &KEY);
+        self.at_panic(Some(panic));
+    }
+
+    /// Sets the global `name` to a function calling the boxed closure `f`, which returns the count of its results
+    /// on the stack like a `lua_CFunction`. The closure is stored in a userdata upvalue, and dropped by its `__gc`
+    pub fn register_boxed(&self, name: &str, f: Box<dyn Fn(&State) -> c_int>) {
+        type BoxedFn = Box<dyn Fn(&State) -> c_int>;
+
+        unsafe extern "C" fn call(l: *mut lua_State) -> c_int {
+            let s = State::from_ptr(l);
+            match s.to_userdata_typed::<BoxedFn>(lua_upvalueindex(1)) {
+                Some(f) => (*f)(&s),
+                None => 0,
+            }
+        }
+
+        let g = self.global();
+        self.push(name);
+        self.push_userdatauv::<BoxedFn>(f, 0);
+        let mt = self.table(0, 1);
+        mt.set("__gc", __gc::<BoxedFn> as CFunction);
+        self.set_metatable(-2);
+        self.push_cclosure(Some(call), 1);
+        self.set_table(g.index);
+        self.pop(1);
+    }
+
+    /// Calls `f` when the memory in use grows over `threshold_bytes`, checked by a count hook every
+    /// 1000 instructions, `f` isn't called again until the memory drops below the threshold.
+    /// The hook already set on this state is chained and still called for its own events, if it's
+    /// also a count hook, its count is used instead of 1000. Calling it again replaces the previous `f`,
+    /// and the coroutines created later inherit the hook
+    pub fn on_gc_pressure(&self, threshold_bytes: usize, f: impl Fn(&State) + 'static) {
+        struct GcPressure {
+            threshold: usize,
+            above: bool,
+            f: Box<dyn Fn(&State)>,
+            prev: lua_Hook,
+            prev_mask: c_int,
+        }
+        static KEY: u8 = 0;
+
+        unsafe fn get(s: &State) -> Option<*mut GcPressure> {
+            if !s.check_stack(1) {
+                return None;
+            }
+            s.raw_getp(LUA_REGISTRYINDEX, &KEY);
+            let p = s
+                .to_userdata_typed::<GcPressure>(-1)
+                .map(|p| p as *mut GcPressure);
+            // the userdata is still referenced by the registry
+            s.pop(1);
+            p
+        }
+
+        extern "C" fn hook(l: *mut lua_State, ar: *mut lua_Debug) {
+            let s = unsafe { State::from_ptr(l) };
+            let Some(p) = (unsafe { get(&s) }).map(|p| unsafe { &mut *p }) else {
+                return;
+            };
+            let (prev, prev_mask) = (p.prev, p.prev_mask);
+            let event = unsafe { (*ar).event };
+            if event == LUA_HOOKCOUNT {
+                let above = s.memory_used() >= p.threshold;
+                if above && !p.above {
+                    p.above = true;
+                    (p.f)(&s);
+                } else if !above {
+                    p.above = false;
+                }
+            }
+            let mask = match event {
+                LUA_HOOKTAILCALL => LUA_MASKCALL,
+                event => 1 << event,
+            };
+            if let Some(prev) = prev.filter(|_| prev_mask & mask != 0) {
+                prev(l, ar);
+            }
+        }
+
+        let _balance = BalanceState::new(self);
+        let l = self.as_ptr();
+        let (mut prev, mut prev_mask) = unsafe { (lua_gethook(l), lua_gethookmask(l)) };
+        if prev.is_some_and(|prev| core::ptr::fn_addr_eq(prev, hook as extern "C" fn(_, _))) {
+            // chains to the hook before the replaced callback
+            (prev, prev_mask) = unsafe { get(self) }
+                .map(|p| unsafe { ((*p).prev, (*p).prev_mask) })
+                .unwrap_or((None, 0));
+        }
+        let count = match prev {
+            Some(_) if prev_mask & LUA_MASKCOUNT != 0 => unsafe { lua_gethookcount(l) },
+            _ => 1000,
+        };
+        self.push_userdatauv(
+            GcPressure {
+                threshold: threshold_bytes,
+                above: false,
+                f: Box::new(f),
+                prev,
+                prev_mask,
+            },
+            0,
+        );
+        let mt = self.table(0, 1);
+        mt.set("__gc", __gc::<GcPressure> as CFunction);
+        self.set_metatable(-2);
+        self.raw_setp(LUA_REGISTRYINDEX, &KEY);
+        unsafe { lua_sethook(l, Some(hook), prev_mask | LUA_MASKCOUNT, count) };
+    }
+
+    /// Appends a searcher to `package.searchers`, which is called with the module name on `require`,
+    /// and returns the opener of the rust module, so the module is loaded lazily
+    pub fn add_searcher(&self, f: impl Fn(&State, &str) -> Option<CFunction> + 'static) {
+        let _balance = BalanceState::new(self);
+        self.get_global(cstr!("package"));
+        self.get_field(-1, cstr!("searchers"));
+        let searchers = self.val(-1);
+        searchers.check_type(Type::Table);
+        searchers.seti(
+            searchers.raw_len() as lua_Integer + 1,
+            RsFn::new(move |s: &State, name: &str| match f(s, name) {
+                Some(open) => s.pushed(open),
+                None => s.pushed(format!("no rust module '{name}'")),
+            }),
+        );
+    }
+
     #[inline(always)]
     pub fn pushx<T: ToLuaMulti>(&self, t: T) -> c_int {
         t.to_lua(self)
@@ -1148,6 +2094,66 @@ impl State {
         self.xpcall(Self::traceback_c, args)
     }
 
+    /// Calls `string.<name>` in protected mode with the arguments pushed by `push_args`,
+    /// and converts the `nresults` results by `convert` from the index of the first one
+    fn call_string_lib<R>(
+        &self,
+        name: &CStr,
+        push_args: impl FnOnce() -> c_int,
+        nresults: c_int,
+        convert: impl FnOnce(Index) -> Option<R>,
+    ) -> Result<R, Error> {
+        let top = self.get_top();
+        self.push_fn(Some(Self::traceback_c));
+        if self.get_global(cstr!("string")) != Type::Table
+            || self.get_field(-1, name) != Type::Function
+        {
+            self.set_top(top);
+            return Err(Error::runtime("the string library is not loaded"));
+        }
+        self.remove(-2);
+        let nargs = push_args();
+        let result = self
+            .to_error(self.pcall(nargs, nresults, top + 1))
+            .and_then(|_| convert(top + 2).ok_or(Error::ConvertFailed));
+        self.set_top(top);
+        result
+    }
+
+    /// Packs `values` into a binary string by `string.pack(fmt, values...)`,
+    /// the string library must be opened
+    pub fn pack<A: ToLuaMulti>(&self, fmt: &str, values: A) -> Result<Vec<u8>, Error> {
+        self.call_string_lib(
+            cstr!("pack"),
+            || {
+                self.push(fmt);
+                self.pushx(values) + 1
+            },
+            1,
+            |i| self.to_bytes(i).map(<[u8]>::to_vec),
+        )
+    }
+
+    /// Unpacks `data` by `string.unpack(fmt, data)` and converts the unpacked values,
+    /// the trailing position returned by `string.unpack` is dropped, and [`Error::ConvertFailed`]
+    /// is returned if the count of the unpacked values isn't the count of `R`
+    pub fn unpack<R: for<'b> FromLuaMulti<'b>>(&self, fmt: &str, data: &[u8]) -> Result<R, Error> {
+        self.call_string_lib(
+            cstr!("unpack"),
+            || {
+                self.push(fmt);
+                self.push_bytes(data);
+                2
+            },
+            LUA_MULTRET,
+            |i| {
+                (self.get_top() - i == R::COUNT as Index)
+                    .then(|| R::from_lua(self, i))
+                    .flatten()
+            },
+        )
+    }
+
     /// Pushes the given value onto the stack.
     #[inline(always)]
     pub fn push<T: ToLua>(&self, value: T) {
@@ -1192,19 +2198,38 @@ impl State {
 
     #[inline(always)]
     pub fn push_result(&self, r: Result<impl ToLua, impl core::fmt::Debug>, raise: bool) -> c_int {
+        self.push_result_with(
+            r.map_err(|e| format!("{e:?}")),
+            if raise {
+                ErrorMode::Raise
+            } else {
+                ErrorMode::ReturnFalseErr
+            },
+        )
+    }
+
+    /// Pushes the value of `r`, or handles the error by the lua idiom specified by `mode`
+    pub fn push_result_with(
+        &self,
+        r: Result<impl ToLua, impl core::fmt::Display>,
+        mode: ErrorMode,
+    ) -> c_int {
         match r {
             Ok(v) => {
                 self.push(v);
                 1
             }
             Err(e) => {
-                if raise {
-                    self.raise_error(e);
-                } else {
-                    self.push(false);
-                    self.push_string(&format!("{:?}", e));
-                    2
+                let msg = e.to_string();
+                // `lua_error` longjmps over this frame, the error must not outlive the message
+                drop(e);
+                match mode {
+                    ErrorMode::Raise => self.error_string(msg),
+                    ErrorMode::ReturnNilErr => self.push_nil(),
+                    ErrorMode::ReturnFalseErr => self.push(false),
                 }
+                self.push_string(&msg);
+                2
             }
         }
     }
@@ -1220,6 +2245,75 @@ impl ValRef<'_> {
         self.set(k, RsFn::new(v));
         self
     }
+
+    /// [-0, +0, m] Sets `__index` of the metatable to the function, the metatable is created if absent
+    pub fn set_index_fn<'a, V: LuaFn<'a, (), ARGS, RET>, ARGS: 'a, RET: 'a>(&self, v: V) -> &Self {
+        let s = self.state;
+        s.check_stack(3);
+        if !s.get_metatable(self.index) {
+            s.new_table();
+            s.push_value(-1);
+            s.set_metatable(self.index);
+        }
+        s.val(-1).set("__index", RsFn::new(v));
+        s.pop(1);
+        self
+    }
+
+    /// [-0, +0, m] Freezes the table: its entries are moved into a hidden table read through `__index`,
+    /// and assigning any field raises an error. The previous metatable is replaced and `__metatable`
+    /// guards the new one, but `rawset` still bypasses the guard, so don't expose it to untrusted scripts
+    pub fn make_readonly(&self) {
+        let s = self.state;
+        s.check_stack(6);
+        let data = s.table(0, 0);
+        s.push_nil();
+        while s.next(self.index) {
+            s.push_value(-2);
+            s.push_value(-2);
+            s.raw_set(data.index);
+            s.pop(1);
+            s.push_value(-1);
+            s.push_nil();
+            s.raw_set(self.index);
+        }
+
+        let mt = s.table(0, 5);
+        mt.set("__index", &data);
+        mt.set(
+            "__newindex",
+            RsFn::new(|s: &State| -> () { s.error_string("attempt to modify read-only table") }),
+        );
+        mt.set(
+            "__len",
+            RsFn::new(|s: &State| s.val(1).metafield("__index").map(|t| t.raw_len())),
+        );
+        mt.set(
+            "__pairs",
+            RsFn::new(|s: &State| {
+                let data = s.val(1).metafield("__index");
+                s.push_cclosure(Some(Self::readonly_next), 0);
+                s.push_value(data.map(|t| t.index).unwrap_or(1));
+                Pushed(2)
+            }),
+        );
+        mt.set("__metatable", "read-only table");
+        s.set_metatable(self.index);
+        s.pop(1);
+    }
+
+    /// `next` for the hidden table of a read-only table, it's not the global `next`
+    /// since the base library may not be opened
+    unsafe extern "C" fn readonly_next(l: *mut lua_State) -> c_int {
+        let s = State::from_ptr(l);
+        s.set_top(2);
+        if s.next(1) {
+            2
+        } else {
+            s.push_nil();
+            1
+        }
+    }
 }
 
 pub struct MethodRegistry<'a, T, D: ?Sized>(ValRef<'a>, PhantomData<(T, D)>);
@@ -1267,3 +2361,99 @@ where
         self
     }
 }
+
+/// Builds the metatable of a type which can't implement [`UserData`], e.g. a foreign type,
+/// methods and getters get the userdata as the first argument, see [`State::registered_userdata`]
+pub struct MetatableBuilder<'a> {
+    mt: ValRef<'a>,
+    getters: ValRef<'a>,
+    methods: ValRef<'a>,
+}
+
+impl<'a> MetatableBuilder<'a> {
+    /// [-0, +3, m] Pushes the metatable, the getters and the methods tables until [`MetatableBuilder::register`]
+    pub fn new(s: &'a State) -> Self {
+        Self {
+            mt: s.table(0, 4),
+            getters: s.table(0, 0),
+            methods: s.table(0, 0),
+        }
+    }
+
+    pub fn method<'b, V: LuaFn<'b, (), ARGS, RET>, ARGS: 'b, RET: 'b>(
+        self,
+        name: &str,
+        v: V,
+    ) -> Self {
+        self.methods.register(name, v);
+        self
+    }
+
+    /// The getter is called with the userdata when the field `name` is indexed
+    pub fn getter<'b, V: LuaFn<'b, (), ARGS, RET>, ARGS: 'b, RET: 'b>(
+        self,
+        name: &str,
+        v: V,
+    ) -> Self {
+        self.getters.register(name, v);
+        self
+    }
+
+    /// Sets the metamethod `name`, `__index` is overridden by the getters and methods
+    pub fn meta<'b, V: LuaFn<'b, (), ARGS, RET>, ARGS: 'b, RET: 'b>(
+        self,
+        name: &str,
+        v: V,
+    ) -> Self {
+        self.mt.register(name, v);
+        self
+    }
+
+    /// [-3, +0, m] Registers the metatable for the type `T` in this state, and returns the callback
+    /// to use with [`State::push_userdata`], which is distinct for each `T`.
+    /// `__gc` drops the `T` unless it's set by [`MetatableBuilder::meta`]
+    pub fn register<T: 'static>(self) -> InitMetatable {
+        let s = self.mt.state;
+        let init = Self::init::<T> as InitMetatable;
+        self.mt.set("__name", core::any::type_name::<T>());
+        if self.mt.rawget("__gc") == Type::Nil {
+            self.mt.set("__gc", __gc::<T> as CFunction);
+        }
+        s.pop(1);
+        s.push_value(self.getters.index);
+        s.push_value(self.methods.index);
+        s.push_cclosure(Some(Self::__index), 2);
+        self.mt.set_field(cstr!("__index"));
+        s.c_reg().setp(init as *const usize, self.mt);
+        s.pop(3);
+        init
+    }
+
+    /// The metatable of a `T` which isn't registered in the state, it only drops the `T`
+    pub fn init<T: 'static>(mt: &ValRef) {
+        mt.set("__name", core::any::type_name::<T>());
+        mt.set("__gc", __gc::<T> as CFunction);
+    }
+
+    unsafe extern "C" fn __index(l: *mut lua_State) -> c_int {
+        let s = State::from_ptr(l);
+        s.push_value(2);
+        if s.raw_get(lua_upvalueindex(1)) == Type::Function {
+            s.push_value(1);
+            s.call(1, 1);
+        } else {
+            s.pop(1);
+            s.push_value(2);
+            s.raw_get(lua_upvalueindex(2));
+        }
+        1
+    }
+}
+
+impl State {
+    /// The value of a userdata pushed with the callback returned by [`MetatableBuilder::register`]
+    #[inline]
+    pub fn registered_userdata<T: 'static>(&self, i: Index) -> Option<&mut T> {
+        self.test_userdata_meta::<T>(i, MetatableBuilder::init::<T>)
+    }
+}
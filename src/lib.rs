@@ -2,6 +2,7 @@
 #![feature(once_cell)]
 #![feature(const_type_name)]
 #![feature(thread_id_value)]
+#![feature(never_type)]
 #![feature(unboxed_closures)]
 #![feature(min_specialization)]
 #![feature(associated_type_defaults)]
@@ -64,7 +65,31 @@ pub(crate) mod str {
     }
 }
 
+/// Evaluates `$body` and, in debug builds, asserts the stack top changed by `$delta`,
+/// which should match the `[-x, +y]` annotation of the method named `$name`
+macro_rules! stack_delta {
+    ($name:literal, $state:expr, $delta:expr, $body:expr) => {{
+        #[cfg(debug_assertions)]
+        let top = $state.get_top();
+        let result = $body;
+        #[cfg(debug_assertions)]
+        {
+            let actual = $state.get_top() - top;
+            assert!(
+                actual == $delta,
+                "stack delta of {} is {}, expected {}",
+                $name,
+                actual,
+                $delta
+            );
+        }
+        result
+    }};
+}
+
 mod r#async;
+#[cfg(feature = "compat")]
+pub mod compat;
 mod convert;
 #[cfg(all(feature = "thread", feature = "vendored"))]
 mod llua;
@@ -79,16 +104,26 @@ mod value;
 
 pub use self::serde::*;
 pub use convert::*;
+#[cfg(feature = "derive")]
+pub use llua_derive::*;
 pub use lmacro::*;
 pub use r#async::*;
 pub use state::*;
 pub use util::*;
 pub use value::*;
 
+// used by the code generated by the derive macros, so the users needn't depend on serde
+#[doc(hidden)]
+pub mod __private {
+    pub use ::serde;
+}
+
 #[cfg(feature = "thread")]
 pub mod thread {
     use super::{ffi::lua_State, *};
-    use core::cell::Cell;
+    use alloc::rc::Rc;
+    use core::any::Any;
+    use core::cell::{Cell, RefCell};
     use parking_lot::Mutex;
     use std::sync::LazyLock;
 
@@ -103,14 +138,14 @@ pub mod thread {
     });
 
     #[derive(derive_more::Deref)]
-    pub struct TlsState(Cell<*mut lua_State>);
+    pub struct TlsState(#[deref] Cell<*mut lua_State>, RefCell<Option<Rc<dyn Any>>>);
 
     impl TlsState {
         fn new() -> TlsState {
-            Self(Cell::new(core::ptr::null_mut()))
+            Self(Cell::new(core::ptr::null_mut()), RefCell::new(None))
         }
 
-        fn get(&self) -> State {
+        fn get(&self) -> StateRef<'static> {
             if self.0.get().is_null() {
                 unsafe {
                     let s = GLOBAL_LUA.lock();
@@ -139,9 +174,20 @@ pub mod thread {
         static LUA: TlsState = TlsState::new();
     }
 
-    pub fn state() -> State {
+    /// The lua thread of current thread, which is owned by the global state
+    pub fn state() -> StateRef<'static> {
         LUA.with(TlsState::get)
     }
+
+    /// Associates the data with the lua state of current thread, replacing the previous one
+    pub fn set_context<T: 'static>(data: T) {
+        LUA.with(|tls| tls.1.replace(Some(Rc::new(data))));
+    }
+
+    /// Gets the data set by [`set_context`] on current thread, if its type is `T`
+    pub fn context<T: 'static>() -> Option<Rc<T>> {
+        LUA.with(|tls| tls.1.borrow().clone()?.downcast().ok())
+    }
 }
 
 pub mod error {
@@ -159,6 +205,8 @@ pub mod error {
         #[from(ignore)]
         Gc(String),
         Yield,
+        /// A string passed to the C API contains an interior NUL byte
+        NulByte,
         #[from(ignore)]
         Convert(Box<dyn Debug>),
         ConvertFailed,
@@ -55,6 +55,24 @@ macro_rules! cfn {
     };
 }
 
+/// Implements [`BitFlags`](crate::BitFlags) for types generated by `bitflags!`
+#[macro_export]
+macro_rules! impl_bitflags {
+    ($($t:ty),* $(,)?) => {$(
+        impl $crate::BitFlags for $t {
+            #[inline(always)]
+            fn from_bits(bits: $crate::ffi::lua_Integer) -> Option<Self> {
+                <$t>::from_bits(::core::convert::TryInto::try_into(bits).ok()?)
+            }
+
+            #[inline(always)]
+            fn bits(&self) -> $crate::ffi::lua_Integer {
+                <$t>::bits(self) as _
+            }
+        }
+    )*};
+}
+
 #[macro_export]
 macro_rules! metatable {
     (@method $t:ty, $s:ident, ($($this:tt)*) ($($arg_def:tt)*) $($body_option:ident)? $body:block) => {
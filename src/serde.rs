@@ -1,11 +1,14 @@
 //! [serde](https://crates.io/crates/serde) utilities for lua
 
 use super::*;
-use crate::{ffi::*, CRegVal, FromLua, State, ToLua, Type, ValRef};
+use crate::{ffi::*, CRegVal, FromLua, Index, State, ToLua, Type, ValRef};
 use alloc::fmt::{self, Display};
 #[rustfmt::skip]
 use ::serde::{
-    de::{Deserialize, DeserializeSeed, Deserializer, Error as DeErr, MapAccess, SeqAccess, Visitor},
+    de::{
+        DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, Error as DeErr, MapAccess, SeqAccess,
+        VariantAccess, Visitor,
+    },
     ser::{
         Error, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
         SerializeTuple, SerializeTupleStruct, SerializeTupleVariant, Serializer,
@@ -44,6 +47,23 @@ pub enum DesErr {
 #[cfg(feature = "std")]
 impl std::error::Error for DesErr {}
 
+/// Error of serializing a value to lua by [`State::push_serialize`]
+#[derive(Clone, Debug, PartialEq, Display)]
+pub enum SerErr {
+    Message(String),
+    /// A map key which can't be a table key, e.g. NaN, the message names the key
+    InvalidKey(String),
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SerErr {}
+
+impl Error for SerErr {
+    fn custom<T: Display>(msg: T) -> Self {
+        SerErr::Message(msg.to_string())
+    }
+}
+
 impl DeErr for DesErr {
     fn custom<T: Display>(msg: T) -> Self {
         DesErr::Message(msg.to_string())
@@ -52,16 +72,22 @@ impl DeErr for DesErr {
 
 impl State {
     #[inline(always)]
-    pub fn push_serialize<V: Serialize>(&self, v: V) -> Result<(), core::fmt::Error> {
+    pub fn push_serialize<V: Serialize>(&self, v: V) -> Result<(), SerErr> {
         v.serialize(LuaSerializer(self))
     }
+
+    /// [-0, +1, -] Pushes the value read from a serde deserializer, e.g. a data format like toml or yaml
+    #[inline(always)]
+    pub fn push_from_deserializer<'de, D: Deserializer<'de>>(&self, d: D) -> Result<(), D::Error> {
+        DeLua(self).deserialize(d)
+    }
 }
 
 #[derive(Copy, Clone, Deref, DerefMut)]
 pub struct SerdeValue<T>(pub T);
 
 impl<T: Serialize> ToLua for SerdeValue<T> {
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     #[inline(always)]
     fn to_lua(self, s: &State) {
@@ -75,16 +101,69 @@ impl<T: Serialize> ToLua for SerdeValue<T> {
     }
 }
 
-impl<'a, T: Deserialize<'a> + 'a> FromLua<'a> for SerdeValue<T> {
+/// Represents a rust error raised to lua as the value serialized from `E`, e.g. a table with the
+/// fields of a struct, which can be inspected by the caller of `pcall` instead of a formatted string
+pub struct ErrorObject<E>(pub E);
+
+impl<E: Serialize> From<E> for ErrorObject<E> {
+    #[inline(always)]
+    fn from(e: E) -> Self {
+        Self(e)
+    }
+}
+
+impl<T: ToLuaMulti, E: Serialize + fmt::Debug + 'static> ToLuaMulti for Result<T, ErrorObject<E>> {
+    #[inline(always)]
+    fn to_lua(self, s: &State) -> libc::c_int {
+        match self {
+            Ok(val) => val.to_lua(s),
+            Err(e) => {
+                let top = s.get_top();
+                if let Err(err) = s.push_serialize(&e.0) {
+                    s.set_top(top);
+                    s.raise_error(err);
+                }
+                s.error()
+            }
+        }
+    }
+
+    /// The error object can't be passed to rust as a lua value, so it's formatted by `Debug`
+    #[inline(always)]
+    fn to_lua_result(self, s: &State) -> Result<libc::c_int, crate::error::Error> {
+        self.map(|val| val.to_lua(s))
+            .map_err(|e| crate::error::Error::from_debug(e.0))
+    }
+}
+
+// Lua strings are only alive while referenced from the stack or a table, so borrowed data
+// can't outlive the conversion, see `ValRef::deserialize`
+impl<'a, T: DeserializeOwned + 'a> FromLua<'a> for SerdeValue<T> {
     #[inline(always)]
     fn from_lua(s: &'a State, i: i32) -> Option<SerdeValue<T>> {
         Some(SerdeValue(T::deserialize(s.val(i)).ok()?))
     }
 }
 
+/// The entries are in the order of `lua_next`, and the keys can be any deserializable type
+#[cfg(feature = "indexmap")]
+impl<'a, K, V, S> FromLua<'a> for indexmap::IndexMap<K, V, S>
+where
+    K: DeserializeOwned + Eq + core::hash::Hash + 'a,
+    V: DeserializeOwned + 'a,
+    S: core::hash::BuildHasher + Default + 'a,
+{
+    #[inline(always)]
+    fn from_lua(s: &'a State, i: i32) -> Option<Self> {
+        s.val(i).deserialize().ok()
+    }
+}
+
 impl<'a> ValRef<'a> {
+    /// Deserializes the value, strings and bytes are always copied because the lua value
+    /// may be popped or collected while the result is still alive
     #[inline(always)]
-    pub fn deserialize<T: Deserialize<'a>>(&self) -> Result<T, DesErr> {
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, DesErr> {
         T::deserialize(*self)
     }
 }
@@ -94,7 +173,7 @@ struct LuaTableSerializer<'a>(&'a State, i64);
 
 impl SerializeSeq for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -113,7 +192,7 @@ impl SerializeSeq for LuaTableSerializer<'_> {
 
 impl SerializeTuple for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -129,7 +208,7 @@ impl SerializeTuple for LuaTableSerializer<'_> {
 
 impl SerializeTupleStruct for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -145,7 +224,7 @@ impl SerializeTupleStruct for LuaTableSerializer<'_> {
 
 impl SerializeTupleVariant for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -173,7 +252,7 @@ impl<'a> LuaTableSerializer<'a> {
 
 impl SerializeStruct for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_field<T: ?Sized>(
         &mut self,
@@ -194,13 +273,17 @@ impl SerializeStruct for LuaTableSerializer<'_> {
 
 impl SerializeMap for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
     where
         T: Serialize,
     {
-        self.0.push_serialize(key)
+        self.0.push_serialize(key)?;
+        check_table_key(self.0, -1).map_err(|err| {
+            self.0.pop(1);
+            SerErr::InvalidKey(err)
+        })
     }
 
     fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
@@ -219,7 +302,7 @@ impl SerializeMap for LuaTableSerializer<'_> {
 
 impl SerializeStructVariant for LuaTableSerializer<'_> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
 
     fn serialize_field<T: ?Sized>(
         &mut self,
@@ -239,7 +322,7 @@ impl SerializeStructVariant for LuaTableSerializer<'_> {
 
 impl<'a> Serializer for LuaSerializer<'a> {
     type Ok = ();
-    type Error = core::fmt::Error;
+    type Error = SerErr;
     type SerializeSeq = LuaTableSerializer<'a>;
     type SerializeMap = LuaTableSerializer<'a>;
     type SerializeTuple = LuaTableSerializer<'a>;
@@ -417,10 +500,10 @@ impl<'a> Serializer for LuaSerializer<'a> {
     }
 
     fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
-        Err(core::fmt::Error)
+        Err(SerErr::custom("i128 is not supported"))
     }
     fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
-        Err(core::fmt::Error)
+        Err(SerErr::custom("u128 is not supported"))
     }
 
     // fn collect_str<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
@@ -464,6 +547,8 @@ impl<'de> Deserializer<'de> for ValRef<'de> {
                     self.deserialize_map(visitor)
                 }
             }
+            // an absent value isn't nil, e.g. an argument beyond the top
+            Type::None => visitor.visit_none(),
             _ => visitor.visit_unit(),
         }
     }
@@ -586,7 +671,7 @@ impl<'de> Deserializer<'de> for ValRef<'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_str(
+        visitor.visit_str(
             self.state
                 .to_str(self.index)
                 .ok_or(DesErr::ExpectedString)?,
@@ -618,7 +703,7 @@ impl<'de> Deserializer<'de> for ValRef<'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_bytes(
+        visitor.visit_bytes(
             self.state
                 .to_bytes(self.index)
                 .ok_or(DesErr::ExpectedString)?,
@@ -766,7 +851,11 @@ impl<'de> Deserializer<'de> for ValRef<'de> {
                 T: DeserializeSeed<'de>,
             {
                 if self.state.next(self.index) {
-                    Ok(Some(seed.deserialize(self.state.val(-2))?))
+                    // deserialize a copy, converting the key in place by lua_tolstring confuses lua_next
+                    self.state.push_value(-2);
+                    let r = seed.deserialize(self.state.val(-1));
+                    self.state.pop(1);
+                    Ok(Some(r?))
                 } else {
                     Ok(None)
                 }
@@ -816,7 +905,81 @@ impl<'de> Deserializer<'de> for ValRef<'de> {
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        struct EnumDes<'de> {
+            variant: ValRef<'de>,
+            value: Option<ValRef<'de>>,
+        }
+
+        impl<'de> EnumAccess<'de> for EnumDes<'de> {
+            type Error = DesErr;
+            type Variant = Self;
+
+            fn variant_seed<T>(self, seed: T) -> Result<(T::Value, Self), Self::Error>
+            where
+                T: DeserializeSeed<'de>,
+            {
+                Ok((seed.deserialize(self.variant)?, self))
+            }
+        }
+
+        impl<'de> VariantAccess<'de> for EnumDes<'de> {
+            type Error = DesErr;
+
+            fn unit_variant(self) -> Result<(), Self::Error> {
+                Ok(())
+            }
+
+            fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+            where
+                T: DeserializeSeed<'de>,
+            {
+                seed.deserialize(self.value.ok_or(DesErr::ExpectedEnum)?)
+            }
+
+            fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                self.value
+                    .ok_or(DesErr::ExpectedEnum)?
+                    .deserialize_seq(visitor)
+            }
+
+            fn struct_variant<V>(
+                self,
+                fields: &'static [&'static str],
+                visitor: V,
+            ) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                self.value
+                    .ok_or(DesErr::ExpectedEnum)?
+                    .deserialize_map(visitor)
+            }
+        }
+
+        // a unit variant is represented by its name, and others by a table like `{Variant = value}`
+        match self.state.type_of(self.index) {
+            Type::String => visitor.visit_enum(EnumDes {
+                variant: self,
+                value: None,
+            }),
+            Type::Table => {
+                let top = self.state.get_top();
+                self.state.push_nil();
+                if !self.state.next(self.index) {
+                    return Err(DesErr::ExpectedEnum);
+                }
+                let result = visitor.visit_enum(EnumDes {
+                    variant: self.state.val(top + 1),
+                    value: Some(self.state.val(top + 2)),
+                });
+                self.state.set_top(top);
+                result
+            }
+            _ => Err(DesErr::ExpectedEnum),
+        }
     }
 
     /// Hint that the `Deserialize` type is expecting the name of a struct
@@ -948,10 +1111,17 @@ impl<'de> Visitor<'de> for LuaVisitor<'_> {
         let size = map.size_hint();
         self.0.create_table(0, size.unwrap_or_default() as _);
 
+        let check_key = || {
+            check_table_key(self.0, -1).map_err(|err| {
+                self.0.pop(1);
+                A::Error::custom(err)
+            })
+        };
         if let Some(size) = size {
             for _ in 1..=size {
                 match map.next_key_seed(DeLua(self.0))? {
                     Some(_) => {
+                        check_key()?;
                         map.next_value_seed(DeLua(self.0))?;
                         self.0.raw_set(-3);
                     }
@@ -960,6 +1130,7 @@ impl<'de> Visitor<'de> for LuaVisitor<'_> {
             }
         } else {
             while let Some(_) = map.next_key_seed(DeLua(self.0))? {
+                check_key()?;
                 map.next_value_seed(DeLua(self.0))?;
                 self.0.raw_set(-3);
             }
@@ -969,12 +1140,156 @@ impl<'de> Visitor<'de> for LuaVisitor<'_> {
     }
 }
 
+/// Options for serializing lua values, see [`ValRef::serialize_with`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// emit map entries ordered by key: numeric keys first (numerically), then
+    /// string keys (lexicographically), then other keys in `lua_next` order
+    pub sort_keys: bool,
+    /// serialize functions and userdata as `{"__lua_function": id}` and `{"__lua_userdata": id}`,
+    /// where the id is a reference in the registry, see [`State::restore_handles`]. Otherwise
+    /// serializing a function is an error and userdata is serialized as none
+    pub handles: bool,
+    /// serialize the number keys of maps as strings, for the formats only support string keys, like toml
+    pub string_keys: bool,
+}
+
+const FUNCTION_HANDLE: &str = "__lua_function";
+const USERDATA_HANDLE: &str = "__lua_userdata";
+
+impl State {
+    /// Replaces the handles in the value at `i` serialized with [`SerializeOptions::handles`],
+    /// recursively, by the functions and userdata they refer to, the tables mustn't be cyclic. The handles stay valid, and the
+    /// registry references can be released by [`State::unreference`]
+    pub fn restore_handles(&self, i: Index) {
+        let i = self.abs_index(i);
+        if self.type_of(i) != Type::Table {
+            return;
+        }
+        self.check_stack(4);
+        if let Some((ty, id)) = self.handle_of(i) {
+            if self.raw_geti(LUA_REGISTRYINDEX, id) == ty {
+                self.replace(i);
+            } else {
+                self.pop(1);
+            }
+            return;
+        }
+        self.push_nil();
+        while self.next(i) {
+            if self.type_of(-1) == Type::Table {
+                self.restore_handles(-1);
+                self.push_value(-2);
+                self.insert(-2);
+                self.raw_set(i);
+            } else {
+                self.pop(1);
+            }
+        }
+    }
+
+    // [-0, +0] Checks if the table at `i` is a handle, which has only one field
+    fn handle_of(&self, i: Index) -> Option<(Type, lua_Integer)> {
+        let top = self.get_top();
+        self.push_nil();
+        if !self.next(i) {
+            return None;
+        }
+        // don't convert the key in place by `to_bytes`, which confuses `next`
+        let ty = match self.type_of(-2) {
+            Type::String => match self.to_bytes(-2) {
+                Some(k) if k == FUNCTION_HANDLE.as_bytes() => Some(Type::Function),
+                Some(k) if k == USERDATA_HANDLE.as_bytes() => Some(Type::Userdata),
+                _ => None,
+            },
+            _ => None,
+        };
+        let id = self.is_integer(-1).then(|| self.to_integer(-1));
+        self.pop(1);
+        let only = !self.next(i);
+        self.set_top(top);
+        ty.zip(id).filter(|_| only)
+    }
+}
+
+/// A lua value to be serialized with the specified options
+#[derive(Clone, Copy)]
+pub struct SerializeWith<'a>(pub ValRef<'a>, pub SerializeOptions);
+
+impl<'a> ValRef<'a> {
+    #[inline(always)]
+    pub fn serialize_with(self, options: SerializeOptions) -> SerializeWith<'a> {
+        SerializeWith(self, options)
+    }
+}
+
+enum SortKey<'a> {
+    Int(lua_Integer),
+    Num(lua_Number),
+    Str(&'a [u8]),
+    Other,
+}
+
+impl<'a> SortKey<'a> {
+    fn of(s: &'a State, i: Index) -> Self {
+        match s.type_of(i) {
+            Type::Number if s.is_integer(i) => Self::Int(s.to_integer(i)),
+            Type::Number => Self::Num(s.to_number(i)),
+            Type::String => Self::Str(s.to_bytes(i).unwrap_or_default()),
+            _ => Self::Other,
+        }
+    }
+
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        use core::cmp::Ordering::*;
+
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => a.cmp(b),
+            (Self::Int(a), Self::Num(b)) => (*a as lua_Number).partial_cmp(b).unwrap_or(Equal),
+            (Self::Num(a), Self::Int(b)) => a.partial_cmp(&(*b as lua_Number)).unwrap_or(Equal),
+            (Self::Num(a), Self::Num(b)) => a.partial_cmp(b).unwrap_or(Equal),
+            (Self::Int(_) | Self::Num(_), _) => Less,
+            (_, Self::Int(_) | Self::Num(_)) => Greater,
+            (Self::Str(a), Self::Str(b)) => a.cmp(b),
+            (Self::Str(_), Self::Other) => Less,
+            (Self::Other, Self::Str(_)) => Greater,
+            (Self::Other, Self::Other) => Equal,
+        }
+    }
+}
+
 impl Serialize for ValRef<'_> {
+    #[inline(always)]
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializeWith(*self, Default::default()).serialize(serializer)
+    }
+}
+
+struct MapKey<'a>(ValRef<'a>, SerializeOptions);
+
+impl Serialize for MapKey<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let MapKey(key, options) = *self;
+        // lua_tostring would convert the key in place and confuse lua_next
+        if options.string_keys && key.type_of() == Type::Number {
+            if key.is_integer() {
+                serializer.collect_str(&key.state.to_integer(key.index))
+            } else {
+                serializer.collect_str(&key.state.to_number(key.index))
+            }
+        } else {
+            key.serialize_with(options).serialize(serializer)
+        }
+    }
+}
+
+impl Serialize for SerializeWith<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let SerializeWith(this, options) = *self;
         unsafe {
-            match lua_type(self.state.as_ptr(), self.index) {
+            match lua_type(this.state.as_ptr(), this.index) {
                 LUA_TSTRING => {
-                    let bytes = self.state.to_bytes(self.index).unwrap_or_default();
+                    let bytes = this.state.to_bytes(this.index).unwrap_or_default();
                     // TODO:
                     if bytes.len() > 0x1000 {
                         serializer.serialize_bytes(bytes)
@@ -987,48 +1302,103 @@ impl Serialize for ValRef<'_> {
                 }
                 // LUA_TSTRING => serializer.serialize_str(self.to_str(self.index).unwrap_or_default()),
                 LUA_TNUMBER => {
-                    if self.is_integer() {
-                        serializer.serialize_i64(self.state.to_integer(self.index))
+                    if this.is_integer() {
+                        serializer.serialize_i64(this.state.to_integer(this.index))
                     } else {
-                        serializer.serialize_f64(self.state.to_number(self.index))
+                        serializer.serialize_f64(this.state.to_number(this.index))
                     }
                 }
-                // TODO: serde option
-                LUA_TFUNCTION => serializer.serialize_bool(true),
-                LUA_TBOOLEAN => serializer.serialize_bool(self.to_bool()),
+                LUA_TFUNCTION | LUA_TUSERDATA if options.handles => {
+                    this.state.push_value(this.index);
+                    let r = this.state.reference(LUA_REGISTRYINDEX);
+                    let key = if this.type_of() == Type::Function {
+                        FUNCTION_HANDLE
+                    } else {
+                        USERDATA_HANDLE
+                    };
+                    let mut map = serializer.serialize_map(Some(1))?;
+                    map.serialize_entry(key, &(r.0 as i64))?;
+                    map.end()
+                }
+                LUA_TFUNCTION => Err(S::Error::custom(
+                    "can't serialize a function without SerializeOptions::handles",
+                )),
+                LUA_TBOOLEAN => serializer.serialize_bool(this.to_bool()),
                 LUA_TTABLE => {
-                    let len = self.state.raw_len(self.index) as usize;
-                    self.state
-                        .check_stack(3)
+                    let len = this.state.raw_len(this.index) as usize;
+                    this.state
+                        .check_stack(4)
                         .then_some(())
                         .ok_or_else(|| S::Error::custom("stack not enough"))?;
                     if len > 0 {
                         let mut seq = serializer.serialize_seq(Some(len))?;
                         for i in 1..=len {
-                            self.state.raw_geti(self.index, i as lua_Integer);
-                            let res = seq.serialize_element(&self.state.val(-1));
-                            self.state.pop(1);
+                            this.state.raw_geti(this.index, i as lua_Integer);
+                            let res =
+                                seq.serialize_element(&this.state.val(-1).serialize_with(options));
+                            this.state.pop(1);
                             res?;
                         }
                         seq.end()
                     } else {
                         // get count of entries in the table
                         let mut count = 0usize;
-                        self.state.push_nil();
-                        while lua_next(self.state.as_ptr(), self.index) != 0 {
+                        this.state.push_nil();
+                        while lua_next(this.state.as_ptr(), this.index) != 0 {
                             count += 1;
-                            self.state.pop(1);
+                            this.state.pop(1);
                         }
                         // serialize empty table as empty array
                         if count == 0 {
                             serializer.serialize_seq(Some(len))?.end()
+                        } else if options.sort_keys {
+                            let top = this.state.get_top();
+                            // keep the keys alive in a temporary array while sorting them
+                            let keys = this.state.table(count as _, 0);
+                            let mut i = 0;
+                            this.state.push_nil();
+                            while lua_next(this.state.as_ptr(), this.index) != 0 {
+                                this.state.pop(1);
+                                i += 1;
+                                this.state.push_value(-1);
+                                this.state.raw_seti(keys.index, i);
+                            }
+                            let mut order = (1..=i)
+                                .map(|i| {
+                                    this.state.raw_geti(keys.index, i);
+                                    let key = SortKey::of(this.state, -1);
+                                    this.state.pop(1);
+                                    (i, key)
+                                })
+                                .collect::<Vec<_>>();
+                            order.sort_by(|a, b| a.1.cmp(&b.1));
+
+                            let mut map = serializer.serialize_map(Some(count))?;
+                            for (i, _) in order {
+                                this.state.raw_geti(keys.index, i);
+                                this.state.push_value(-1);
+                                this.state.raw_get(this.index);
+                                let res = map.serialize_entry(
+                                    &MapKey(this.state.val(-2), options),
+                                    &this.state.val(-1).serialize_with(options),
+                                );
+                                this.state.pop(2);
+                                if res.is_err() {
+                                    this.state.set_top(top);
+                                }
+                                res?;
+                            }
+                            this.state.set_top(top);
+                            map.end()
                         } else {
                             let mut map = serializer.serialize_map(Some(count))?;
-                            self.state.push_nil();
-                            while lua_next(self.state.as_ptr(), self.index) != 0 {
-                                let res =
-                                    map.serialize_entry(&self.state.val(-2), &self.state.val(-1));
-                                self.state.pop(1);
+                            this.state.push_nil();
+                            while lua_next(this.state.as_ptr(), this.index) != 0 {
+                                let res = map.serialize_entry(
+                                    &MapKey(this.state.val(-2), options),
+                                    &this.state.val(-1).serialize_with(options),
+                                );
+                                this.state.pop(1);
                                 res?;
                             }
                             map.end()
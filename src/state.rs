@@ -6,12 +6,22 @@ use alloc::borrow::Cow;
 use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::{mem, ptr, slice, str};
 use libc::{c_char, c_int, c_void, size_t};
 
 pub type InitMetatable = fn(&ValRef);
 
+fn to_cstring(s: &str) -> Result<CString, Error> {
+    CString::new(s).map_err(|_| Error::NulByte)
+}
+
+/// the string is truncated at the first NUL byte, as the C API would see it
+fn to_cstring_lossy(s: &str) -> CString {
+    CString::new(s.split('\0').next().unwrap_or_default()).unwrap_or_default()
+}
+
 /// Arithmetic operations for `lua_arith`.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Arithmetic {
@@ -84,6 +94,16 @@ impl ThreadStatus {
         }
     }
 
+    /// Converts the status to a result with the error value on the top, which is popped,
+    /// `Yield` is treated as success
+    pub fn into_result(self, s: &State) -> Result<(), Error> {
+        match self {
+            Self::Yield => Ok(()),
+            _ => s.to_error(self),
+        }
+    }
+
+    /// Panics with the error value on the top, intended for tests, see [`ThreadStatus::into_result`]
     pub fn chk_err(self, s: &State) {
         if self != Self::Ok {
             panic!("{}", s.to_str(-1).unwrap_or("<error>"));
@@ -111,6 +131,61 @@ pub enum GcOption {
     SetPause = LUA_GCSETPAUSE as isize,
     SetStepMul = LUA_GCSETSTEPMUL as isize,
     IsRunning = LUA_GCISRUNNING as isize,
+    /// Switches to the generational mode keeping its parameters, see [`State::gc_set_mode`] to set them
+    Generational = LUA_GCGEN as isize,
+    /// Switches to the incremental mode keeping its parameters, see [`State::gc_set_mode`] to set them
+    Incremental = LUA_GCINC as isize,
+}
+
+/// Mode of the Lua garbage collector, see [`State::gc_set_mode`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcMode {
+    Incremental,
+    Generational,
+}
+
+/// Parameters passed along with [`GcMode`], a zero value keeps the current setting
+///
+/// `pause`, `stepmul` and `stepsize` are used by the incremental mode,
+/// `minormul` and `majormul` by the generational mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcParams {
+    pub pause: c_int,
+    pub stepmul: c_int,
+    pub stepsize: c_int,
+    pub minormul: c_int,
+    pub majormul: c_int,
+}
+
+/// Owned information of an active function, filled by `lua_getinfo` with `"nSl"`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub name: Option<String>,
+    pub namewhat: String,
+    pub what: String,
+    pub source: String,
+    pub short_src: String,
+    pub current_line: c_int,
+    pub line_defined: c_int,
+    pub last_line_defined: c_int,
+}
+
+impl DebugInfo {
+    fn from_raw(ar: &lua_Debug) -> Self {
+        let string = |p: *const c_char| {
+            (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned())
+        };
+        Self {
+            name: string(ar.name),
+            namewhat: string(ar.namewhat).unwrap_or_default(),
+            what: string(ar.what).unwrap_or_default(),
+            source: string(ar.source).unwrap_or_default(),
+            short_src: string(ar.short_src.as_ptr()).unwrap_or_default(),
+            current_line: ar.currentline,
+            line_defined: ar.linedefined,
+            last_line_defined: ar.lastlinedefined,
+        }
+    }
 }
 
 /// Represents all possible Lua data types.
@@ -218,23 +293,53 @@ bitflags::bitflags! {
 #[repr(C)]
 pub struct State(*mut lua_State);
 
+/// A borrowed `State` which doesn't own the `lua_State`, so it can't be closed,
+/// it's what the native functions and callbacks get
+///
+/// ```compile_fail
+/// let s = llua::State::new();
+/// s.new_thread().close();
+/// ```
+#[derive(Debug, Deref)]
+#[repr(transparent)]
+pub struct StateRef<'a>(#[deref] State, PhantomData<&'a State>);
+
 impl State {
     /// Initializes a new Lua state. This function does not open any libraries
     /// by default. Calls `lua_newstate` internally.
+    ///
+    /// Panics if the state could not be allocated, see [`State::try_new`]
     pub fn new() -> State {
-        unsafe { State(luaL_newstate()) }
+        Self::try_new().expect("luaL_newstate failed: out of memory")
+    }
+
+    /// Like [`State::new`], but returns `None` if the state could not be allocated
+    pub fn try_new() -> Option<State> {
+        let l = unsafe { luaL_newstate() };
+        if l.is_null() {
+            None
+        } else {
+            Some(State(l))
+        }
     }
 
-    /// Constructs a wrapper `State` from a raw pointer. This is suitable for use
+    /// Constructs a borrowed [`StateRef`] from a raw pointer. This is suitable for use
     /// inside of native functions that accept a `lua_State` to obtain a wrapper.
     #[inline(always)]
-    pub unsafe fn from_ptr(L: *mut lua_State) -> State {
+    pub unsafe fn from_ptr<'a>(L: *mut lua_State) -> StateRef<'a> {
+        StateRef(State(L), PhantomData)
+    }
+
+    /// Constructs an owning `State` from a raw pointer, which can be closed by [`State::close`],
+    /// use [`State::from_ptr`] unless the caller owns the `lua_State`
+    #[inline(always)]
+    pub unsafe fn from_raw(L: *mut lua_State) -> State {
         State(L)
     }
 
     #[inline(always)]
-    pub unsafe fn copy_state(&self) -> State {
-        State(self.as_ptr())
+    pub unsafe fn copy_state(&self) -> StateRef<'_> {
+        Self::from_ptr(self.as_ptr())
     }
 
     /// Returns an unsafe pointer to the wrapped `lua_State`.
@@ -312,14 +417,14 @@ impl State {
 
     /// Maps to `luaL_dofile`.
     pub fn do_file(&self, filename: &str) -> Result<(), Error> {
-        let c_str = CString::new(filename).unwrap();
+        let c_str = to_cstring(filename)?;
         let result = unsafe { luaL_dofile(self.0, c_str.as_ptr()) };
         self.to_error(ThreadStatus::from_c_int(result))
     }
 
     /// Maps to `luaL_dostring`.
     pub fn do_string(&self, s: &str) -> Result<(), Error> {
-        let c_str = CString::new(s).unwrap();
+        let c_str = to_cstring(s)?;
         let result = unsafe { luaL_dostring(self.0, c_str.as_ptr()) };
         self.to_error(ThreadStatus::from_c_int(result))
     }
@@ -335,9 +440,10 @@ impl State {
         }
     }
 
-    /// [-0, +1, m] Maps to `lua_newthread`.
+    /// [-0, +1, m] Maps to `lua_newthread`, the thread is owned by the state rather than the caller,
+    /// and it's collected once the value pushed is unreachable
     #[inline(always)]
-    pub fn new_thread(&self) -> State {
+    pub fn new_thread(&self) -> StateRef<'_> {
         unsafe { State::from_ptr(lua_newthread(self.0)) }
     }
 
@@ -473,7 +579,7 @@ impl State {
 
     // omitted: lua_tolstring
 
-    /// Maps to `lua_rawlen`.
+    /// Maps to `lua_rawlen`, the length without invoking the `__len` metamethod.
     #[inline(always)]
     pub fn raw_len(&self, index: Index) -> size_t {
         unsafe { lua_rawlen(self.0, index) }
@@ -516,9 +622,9 @@ impl State {
         }
     }
 
-    /// Maps to `lua_tothread`.
+    /// Maps to `lua_tothread`, the thread is borrowed, it's kept alive only while the value is reachable
     #[inline]
-    pub fn to_thread(&self, index: Index) -> Option<State> {
+    pub fn to_thread(&self, index: Index) -> Option<StateRef<'_>> {
         let state = unsafe { lua_tothread(self.0, index) };
         if state.is_null() {
             None
@@ -835,10 +941,60 @@ impl State {
     // Garbage-collection function
     //===========================================================================
     // TODO: return typing?
-    /// Maps to `lua_gc`.
+    /// Maps to `lua_gc`, `data` is ignored by [`GcOption::Generational`] and [`GcOption::Incremental`],
+    /// which return the previous mode as `LUA_GCGEN` or `LUA_GCINC`
     #[inline(always)]
     pub fn gc(&self, what: GcOption, data: c_int) -> c_int {
-        unsafe { lua_gc(self.0, what as c_int, data) }
+        match what {
+            GcOption::Generational | GcOption::Incremental => {
+                let mode = if what == GcOption::Generational {
+                    GcMode::Generational
+                } else {
+                    GcMode::Incremental
+                };
+                match self.gc_set_mode(mode, GcParams::default()) {
+                    GcMode::Generational => LUA_GCGEN,
+                    GcMode::Incremental => LUA_GCINC,
+                }
+            }
+            _ => unsafe { lua_gc(self.0, what as c_int, data) },
+        }
+    }
+
+    /// Switches the garbage collector to `mode` with the related `params`, returns the previous mode
+    pub fn gc_set_mode(&self, mode: GcMode, params: GcParams) -> GcMode {
+        let old = unsafe {
+            match mode {
+                GcMode::Generational => lua_gc(self.0, LUA_GCGEN, params.minormul, params.majormul),
+                GcMode::Incremental => lua_gc(
+                    self.0,
+                    LUA_GCINC,
+                    params.pause,
+                    params.stepmul,
+                    params.stepsize,
+                ),
+            }
+        };
+        if old == LUA_GCGEN {
+            GcMode::Generational
+        } else {
+            GcMode::Incremental
+        }
+    }
+
+    /// Runs a full garbage collection twice, the way to force the pending finalizers (`__gc`) to run
+    /// without closing the state, the second cycle frees the objects resurrected by the finalizers.
+    ///
+    /// Only the unreachable objects are finalized, the values still on the stack or referenced are kept
+    pub fn collect_full(&self) {
+        self.gc(GcOption::Collect, 0);
+        self.gc(GcOption::Collect, 0);
+    }
+
+    /// Returns the memory in use by this state in bytes, combines `LUA_GCCOUNT` and `LUA_GCCOUNTB`
+    #[inline(always)]
+    pub fn memory_used(&self) -> usize {
+        self.gc(GcOption::Count, 0) as usize * 1024 + self.gc(GcOption::CountBytes, 0) as usize
     }
 
     //===========================================================================
@@ -858,22 +1014,46 @@ impl State {
         result != 0
     }
 
+    /// [-1, +(2|0), e] Like [`State::next`], pops the key and returns the next key and value left on the stack,
+    /// pop the value before the next call to keep the key for traversal
+    #[inline(always)]
+    pub fn next_entry<'a>(&'a self, idx: Index) -> Option<(ValRef<'a>, ValRef<'a>)> {
+        self.next(idx).then(|| (self.val(-2), self.val(-1)))
+    }
+
     /// Maps to `lua_concat`.
     #[inline(always)]
     pub fn concat(&self, n: c_int) {
         unsafe { lua_concat(self.0, n) }
     }
 
-    /// Maps to `lua_len`.
+    /// Maps to `lua_toclose`, marks the slot as a to-be-closed variable,
+    /// whose `__close` metamethod is called when the slot is removed from the stack
+    #[inline(always)]
+    pub fn to_close(&self, idx: Index) {
+        unsafe { lua_toclose(self.0, idx) }
+    }
+
+    /// Maps to `lua_closeslot`.
+    #[inline(always)]
+    pub fn close_slot(&self, idx: Index) {
+        unsafe { lua_closeslot(self.0, idx) }
+    }
+
+    /// Maps to `lua_len`, pushes the length.
+    #[deprecated(note = "use State::len_direct or ValRef::len, which return the length")]
     #[inline(always)]
     pub fn len(&self, idx: Index) {
         unsafe { lua_len(self.0, idx) }
     }
 
-    /// Maps to `lua_stringtonumber`.
+    /// Maps to `lua_stringtonumber`, returns 0 without pushing anything if `s` contains a NUL byte,
+    /// which can't be a part of a numeral
     pub fn string_to_number(&self, s: &str) -> size_t {
-        let c_str = CString::new(s).unwrap();
-        unsafe { lua_stringtonumber(self.0, c_str.as_ptr()) }
+        match to_cstring(s) {
+            Ok(c_str) => unsafe { lua_stringtonumber(self.0, c_str.as_ptr()) },
+            Err(_) => 0,
+        }
     }
 
     /// Maps to `lua_getallocf`.
@@ -938,9 +1118,10 @@ impl State {
     }
 
     /// Maps to `lua_register`.
+    /// The strings are truncated at the first NUL byte.
     #[inline(always)]
     pub fn register(&self, n: &str, f: CFunction) {
-        let c_str = CString::new(n).unwrap();
+        let c_str = to_cstring_lossy(n);
         unsafe { lua_register(self.0, c_str.as_ptr(), Some(f)) }
     }
 
@@ -1038,6 +1219,15 @@ impl State {
         }
     }
 
+    /// Iterates the active functions from the current one (level 0) to the outermost
+    pub fn stack_frames(&self) -> impl Iterator<Item = DebugInfo> + '_ {
+        (0..).map_while(|level| {
+            let mut ar = self.get_stack(level)?;
+            self.get_info(cstr!("nSl"), &mut ar);
+            Some(DebugInfo::from_raw(&ar))
+        })
+    }
+
     /// Maps to `lua_getinfo`.
     pub fn get_info(&self, what: &CStr, ar: &mut lua_Debug) -> i32 {
         unsafe { lua_getinfo(self.0, what.as_ptr(), ar) }
@@ -1160,6 +1350,30 @@ impl State {
         unsafe { lua_tocfunction(self.0, index) }
     }
 
+    /// [-0, +0, m] Formats the number exactly like `tostring` in lua
+    pub fn number_to_string(&self, n: lua_Number) -> String {
+        self.push_number(n);
+        self.pop_string()
+    }
+
+    /// [-0, +0, m] Formats the integer exactly like `tostring` in lua
+    pub fn integer_to_string(&self, n: lua_Integer) -> String {
+        self.push_integer(n);
+        self.pop_string()
+    }
+
+    // [-1, +0]
+    fn pop_string(&self) -> String {
+        let mut len = 0;
+        let result = unsafe {
+            let ptr = lua_tolstring(self.0, -1, &mut len);
+            String::from_utf8_lossy(slice::from_raw_parts(ptr as *const u8, len as usize))
+                .into_owned()
+        };
+        self.pop(1);
+        result
+    }
+
     /// Maps to `luaL_tolstring`.
     /// [-0, +1, -]
     #[inline(always)]
@@ -1173,15 +1387,49 @@ impl State {
         }
     }
 
+    /// [-0, +0, e] Converts the value like `tostring` does, respecting `__tostring`,
+    /// into an owned string with invalid UTF-8 replaced, the result of `luaL_tolstring` is popped
+    pub fn tostring_owned(&self, index: Index) -> String {
+        let result = self
+            .cast_string(index)
+            .map(|b| String::from_utf8_lossy(b).into_owned())
+            .unwrap_or_default();
+        self.pop(1);
+        result
+    }
+
+    /// A number is converted to a string in place, which confuses `lua_next` if it's a table key,
+    /// use [`State::to_str_noconv`] to keep the numbers untouched
     #[inline(always)]
     pub fn to_str<'a>(&'a self, index: Index) -> Option<&'a str> {
         self.to_bytes(index)
             .map(|r| unsafe { str::from_utf8_unchecked(r) })
     }
 
+    /// The count of unicode scalar values of the string at `index`, `None` if it's not a string
+    /// or not valid UTF-8, while [`State::raw_len`] is the count of bytes
+    pub fn utf8_len(&self, index: Index) -> Option<usize> {
+        if self.type_of(index) != Type::String {
+            return None;
+        }
+        str::from_utf8(self.to_bytes(index)?)
+            .ok()
+            .map(|s| s.chars().count())
+    }
+
+    /// Like [`State::to_str`], but returns `None` for the values other than strings, without the conversion of numbers
+    #[inline(always)]
+    pub fn to_str_noconv<'a>(&'a self, index: Index) -> Option<&'a str> {
+        if self.type_of(index) == Type::String {
+            self.to_str(index)
+        } else {
+            None
+        }
+    }
+
     /// Maps to `lua_tolstring`, but allows arbitrary bytes.
     /// This function returns a reference to the string at the given index,
-    /// on which `to_owned` may be called.
+    /// on which `to_owned` may be called. A number is converted to a string in place.
     pub fn to_bytes(&self, index: Index) -> Option<&[u8]> {
         let mut len = 0;
         let ptr = unsafe { lua_tolstring(self.0, index, &mut len) };
@@ -1220,6 +1468,12 @@ impl State {
         unsafe { luaL_optnumber(self.0, arg, def) }
     }
 
+    /// Like [`State::opt_number`], but returns `None` for an absent or nil argument instead of a default
+    #[inline]
+    pub fn opt_number_or_none(&self, arg: Index) -> Option<lua_Number> {
+        (!self.is_none_or_nil(arg)).then(|| self.check_number(arg))
+    }
+
     /// Maps to `luaL_checkinteger`.
     #[inline(always)]
     pub fn check_integer(&self, arg: Index) -> lua_Integer {
@@ -1232,9 +1486,16 @@ impl State {
         unsafe { luaL_optinteger(self.0, arg, def) }
     }
 
+    /// Like [`State::opt_integer`], but returns `None` for an absent or nil argument instead of a default
+    #[inline]
+    pub fn opt_integer_or_none(&self, arg: Index) -> Option<lua_Integer> {
+        (!self.is_none_or_nil(arg)).then(|| self.check_integer(arg))
+    }
+
     /// Maps to `luaL_checkstack`.
+    /// The string `msg` is truncated at the first NUL byte.
     pub fn check_stack_msg(&self, sz: c_int, msg: &str) {
-        let c_str = CString::new(msg).unwrap();
+        let c_str = to_cstring_lossy(msg);
         unsafe { luaL_checkstack(self.0, sz, c_str.as_ptr()) }
     }
 
@@ -1295,16 +1556,17 @@ impl State {
     // omitted: luaL_error
 
     /// Maps to `luaL_checkoption`.
+    /// The strings are truncated at the first NUL byte.
     pub fn check_option(&self, arg: Index, def: Option<&str>, lst: &[&str]) -> usize {
         let mut vec: Vec<*const c_char> = Vec::with_capacity(lst.len() + 1);
-        let cstrs: Vec<CString> = lst.iter().map(|ent| CString::new(*ent).unwrap()).collect();
+        let cstrs: Vec<CString> = lst.iter().map(|ent| to_cstring_lossy(ent)).collect();
         for ent in cstrs.iter() {
             vec.push(ent.as_ptr());
         }
         vec.push(ptr::null());
         let result = match def {
             Some(def) => unsafe {
-                let c_str = CString::new(def).unwrap();
+                let c_str = to_cstring_lossy(def);
                 luaL_checkoption(self.0, arg, c_str.as_ptr(), vec.as_ptr())
             },
             None => unsafe { luaL_checkoption(self.0, arg, ptr::null(), vec.as_ptr()) },
@@ -1312,6 +1574,17 @@ impl State {
         result as usize
     }
 
+    /// Like [`State::check_option`], but returns the value paired with the matched option
+    pub fn check_option_map<T: Copy>(
+        &self,
+        arg: Index,
+        def: Option<&str>,
+        pairs: &[(&str, T)],
+    ) -> T {
+        let names: Vec<&str> = pairs.iter().map(|(name, _)| *name).collect();
+        pairs[self.check_option(arg, def, &names)].1
+    }
+
     /// luaL_ref [-1, +0, m]
     #[inline(always)]
     pub fn reference(&self, t: Index) -> Reference {
@@ -1327,25 +1600,24 @@ impl State {
 
     /// Maps to `luaL_loadfilex`.
     pub fn load_filex(&self, filename: &str, mode: &str) -> Result<(), Error> {
-        let result = unsafe {
-            let filename_c_str = CString::new(filename).unwrap();
-            let mode_c_str = CString::new(mode).unwrap();
-            luaL_loadfilex(self.0, filename_c_str.as_ptr(), mode_c_str.as_ptr())
-        };
+        let filename_c_str = to_cstring(filename)?;
+        let mode_c_str = to_cstring(mode)?;
+        let result =
+            unsafe { luaL_loadfilex(self.0, filename_c_str.as_ptr(), mode_c_str.as_ptr()) };
         self.to_error(ThreadStatus::from_c_int(result))
     }
 
     /// Maps to `luaL_loadfile`.
     pub fn load_file(&self, filename: &str) -> Result<(), Error> {
-        let c_str = CString::new(filename).unwrap();
+        let c_str = to_cstring(filename)?;
         let result = unsafe { luaL_loadfile(self.0, c_str.as_ptr()) };
         self.to_error(ThreadStatus::from_c_int(result))
     }
 
     /// Maps to `luaL_loadbufferx`.
     pub fn load_bufferx(&self, buff: &[u8], name: &str, mode: &str) -> Result<(), Error> {
-        let name_c_str = CString::new(name).unwrap();
-        let mode_c_str = CString::new(mode).unwrap();
+        let name_c_str = to_cstring(name)?;
+        let mode_c_str = to_cstring(mode)?;
         let result = unsafe {
             luaL_loadbufferx(
                 self.0,
@@ -1358,12 +1630,14 @@ impl State {
         self.to_error(ThreadStatus::from_c_int(result))
     }
 
-    fn to_error(&self, ts: ThreadStatus) -> Result<(), Error> {
+    /// Converts the status to a result, the error value is popped
+    pub(crate) fn to_error(&self, ts: ThreadStatus) -> Result<(), Error> {
         match ts {
             ThreadStatus::Ok => Ok(()),
             ThreadStatus::Yield => Err(Error::Yield),
             _ => {
                 let err = self.to_str(-1).unwrap_or_default().to_string();
+                self.pop(1);
                 match ts {
                     ThreadStatus::RuntimeError | ThreadStatus::MessageHandlerError => {
                         Err(Error::runtime(err))
@@ -1380,11 +1654,87 @@ impl State {
 
     /// Maps to `luaL_loadstring`.
     pub fn load_string(&self, source: &str) -> Result<(), Error> {
-        let c_str = CString::new(source).unwrap();
+        let c_str = to_cstring(source)?;
         let result = unsafe { luaL_loadstring(self.0, c_str.as_ptr()) };
         self.to_error(ThreadStatus::from_c_int(result))
     }
 
+    /// Loads a chunk like [`State::load_string`], but the chunk is named `name` in the error messages
+    /// and tracebacks, e.g. `my_script:12:`, instead of the source text
+    #[inline]
+    pub fn load_named(&self, src: &str, name: &str) -> Result<(), Error> {
+        self.load_bufferx(src.as_bytes(), &format!("={name}"), "bt")
+    }
+
+    /// [-0, +1, -] Loads a chunk like [`State::load_string`], and replaces its first upvalue `_ENV`
+    /// with the table `env`, so the chunk can access only the fields of `env` as globals
+    pub fn load_with_env(&self, src: &str, env: &ValRef) -> Result<ValRef, Error> {
+        self.load_string(src)?;
+        self.push_value(env.index);
+        if self.set_upvalue(-2, 1).is_none() {
+            self.pop(1);
+        }
+        Ok(self.val(-1))
+    }
+
+    /// [-0, +1, -] Runs the chunk `src` in a fresh environment table and returns its first result.
+    /// The environment only has the safe base functions and copies of the `string`, `table`, `math`
+    /// and `utf8` libraries, the real globals aren't reachable, and the globals written by the chunk
+    /// stay in its own environment, so chunks run by this function can't clobber the globals of each other
+    pub fn run_isolated(&self, src: &str, name: &str) -> Result<ValRef, Error> {
+        const SAFE_GLOBALS: &[&str] = &[
+            "assert",
+            "error",
+            "getmetatable",
+            "ipairs",
+            "next",
+            "pairs",
+            "pcall",
+            "print",
+            "rawequal",
+            "rawget",
+            "rawlen",
+            "rawset",
+            "select",
+            "setmetatable",
+            "tonumber",
+            "tostring",
+            "type",
+            "xpcall",
+            "_VERSION",
+            "string",
+            "table",
+            "math",
+            "utf8",
+        ];
+
+        let env = self.table(0, SAFE_GLOBALS.len() as c_int + 1);
+        let g = self.global();
+        for &key in SAFE_GLOBALS {
+            let val = g.get(key);
+            if val.type_of() == Type::Table {
+                // the libraries are copied, so the chunk can't modify the shared ones
+                let copy = self.table(0, 0);
+                copy.merge(&val, true);
+                env.set(key, &copy);
+                self.pop(1);
+            } else {
+                env.set(key, &val);
+            }
+            self.pop(1);
+        }
+        self.pop(1);
+        env.setf(cstr!("_G"), &env);
+
+        let result = self.load_named(src, name).and_then(|_| {
+            self.push_value(env.index);
+            self.set_upvalue(-2, 1).is_none().then(|| self.pop(1));
+            self.to_error(self.pcall(0, 1, 0))
+        });
+        self.remove(env.index);
+        result.map(|_| self.val(-1))
+    }
+
     /// Maps to `lua_dump`.
     #[inline]
     pub fn dump(&self, mut writer: impl FnMut(&[u8]), strip: bool) -> c_int {
@@ -1410,16 +1760,17 @@ impl State {
         }
     }
 
-    /// Maps to `luaL_len`.
+    /// Maps to `luaL_len`, the length as the `#` operator, raises an error if it's not an integer.
     pub fn len_direct(&self, index: Index) -> lua_Integer {
         unsafe { luaL_len(self.0, index) }
     }
 
     /// Maps to `luaL_gsub`.
+    /// The strings are truncated at the first NUL byte.
     pub fn gsub(&self, s: &str, p: &str, r: &str) -> &str {
-        let s_c_str = CString::new(s).unwrap();
-        let p_c_str = CString::new(p).unwrap();
-        let r_c_str = CString::new(r).unwrap();
+        let s_c_str = to_cstring_lossy(s);
+        let p_c_str = to_cstring_lossy(p);
+        let r_c_str = to_cstring_lossy(r);
         let ptr =
             unsafe { luaL_gsub(self.0, s_c_str.as_ptr(), p_c_str.as_ptr(), r_c_str.as_ptr()) };
         let slice = unsafe { CStr::from_ptr(ptr).to_bytes() };
@@ -1427,12 +1778,11 @@ impl State {
     }
 
     /// Maps to `luaL_setfuncs`.
+    /// The strings are truncated at the first NUL byte.
     pub fn set_fns(&self, l: &[(&str, lua_CFunction)], nup: c_int) {
         let mut reg: Vec<luaL_Reg> = Vec::with_capacity(l.len() + 1);
-        let ents: Vec<(CString, lua_CFunction)> = l
-            .iter()
-            .map(|&(s, f)| (CString::new(s).unwrap(), f))
-            .collect();
+        let ents: Vec<(CString, lua_CFunction)> =
+            l.iter().map(|&(s, f)| (to_cstring_lossy(s), f)).collect();
         for &(ref s, f) in ents.iter() {
             reg.push(luaL_Reg {
                 name: s.as_ptr(),
@@ -1477,9 +1827,10 @@ impl State {
     }
 
     /// Maps to `luaL_argcheck`.
+    /// The string `extramsg` is truncated at the first NUL byte.
     #[inline(always)]
     pub fn arg_check(&self, cond: bool, arg: Index, extramsg: &str) {
-        let c_str = CString::new(extramsg).unwrap();
+        let c_str = to_cstring_lossy(extramsg);
         unsafe { luaL_argcheck(self.0, cond as c_int, arg, c_str.as_ptr()) }
     }
 
@@ -1492,9 +1843,10 @@ impl State {
     }
 
     /// Maps to `luaL_optlstring`.
+    /// The string `default` is truncated at the first NUL byte.
     pub fn opt_string<'a>(&'a mut self, n: Index, default: &'a str) -> &'a str {
         let mut size = 0;
-        let c_str = CString::new(default).unwrap();
+        let c_str = to_cstring_lossy(default);
         let ptr = unsafe { luaL_optlstring(self.0, n, c_str.as_ptr(), &mut size) };
         if ptr == c_str.as_ptr() {
             default
@@ -1512,9 +1864,10 @@ impl State {
     // luaL_dofile and luaL_dostring implemented above
 
     /// Maps to `luaL_getmetatable`.
+    /// The string `tname` is truncated at the first NUL byte.
     #[inline(always)]
     pub fn get_metatable_from_registry(&self, tname: &str) {
-        let c_str = CString::new(tname).unwrap();
+        let c_str = to_cstring_lossy(tname);
         unsafe { luaL_getmetatable(self.0, c_str.as_ptr()) }
     }
 
@@ -1549,10 +1902,68 @@ impl State {
         self.val(-1)
     }
 
+    /// [-0, +1, m]
     #[inline(always)]
     pub fn table(&self, narr: c_int, nrec: c_int) -> ValRef {
-        self.create_table(narr, nrec);
-        self.val(-1)
+        stack_delta!("State::table", self, 1, {
+            self.create_table(narr, nrec);
+            self.val(-1)
+        })
+    }
+
+    /// [-0, +0, -] Sorts the array part of the table at index `t` in place by a rust comparator,
+    /// the comparator may call into lua, and the first error it returns stops the sorting and
+    /// is returned, leaving the table unchanged
+    pub fn sort_table(
+        &self,
+        t: Index,
+        mut cmp: impl FnMut(&ValRef, &ValRef) -> Result<core::cmp::Ordering, Error>,
+    ) -> Result<(), Error> {
+        let t = self.abs_index(t);
+        if self.type_of(t) != Type::Table {
+            return Err(Error::runtime(format!(
+                "table expected, got {}",
+                self.typename_at(t)
+            )));
+        }
+        let len = self.raw_len(t) as lua_Integer;
+        let top = self.get_top();
+        if !self.check_stack(3) {
+            return Err(Error::runtime("stack not enough"));
+        }
+
+        // slice::sort_by can't be stopped, so the comparisons after the first error are skipped
+        let mut error = None;
+        let mut order = (1..=len).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| {
+            if error.is_some() {
+                return core::cmp::Ordering::Equal;
+            }
+            self.raw_geti(t, a);
+            self.raw_geti(t, b);
+            let result = cmp(&self.val(top + 1), &self.val(top + 2));
+            self.set_top(top);
+            result.unwrap_or_else(|err| {
+                error = Some(err);
+                core::cmp::Ordering::Equal
+            })
+        });
+        if let Some(err) = error {
+            return Err(err);
+        }
+
+        // copy the elements first, they would be overwritten while being written back
+        self.create_table(len as _, 0);
+        for i in 1..=len {
+            self.raw_geti(t, i);
+            self.raw_seti(top + 1, i);
+        }
+        for (i, j) in order.into_iter().enumerate() {
+            self.raw_geti(top + 1, j);
+            self.raw_seti(t, i as lua_Integer + 1);
+        }
+        self.set_top(top);
+        Ok(())
     }
 
     /// Register a metatable of UserData into the C registry
@@ -1615,11 +2026,13 @@ impl State {
 
     #[inline(always)]
     pub fn test_userdata_meta_<T>(&self, i: Index, meta: InitMetatable) -> *mut T {
-        if self.get_metatable(i) && {
-            self.raw_getp(LUA_REGISTRYINDEX, meta as *const ());
-            self.raw_equal(-1, -2)
-        } {
-            self.pop(2);
+        if !self.get_metatable(i) {
+            return core::ptr::null_mut();
+        }
+        self.raw_getp(LUA_REGISTRYINDEX, meta as *const ());
+        let matched = self.raw_equal(-1, -2);
+        self.pop(2);
+        if matched {
             self.to_userdata(i) as _
         } else {
             core::ptr::null_mut()
@@ -1699,6 +2112,36 @@ impl State {
         self.to_str(-1).unwrap_or("")
     }
 
+    /// Maps to `lua_getextraspace`, a pointer-sized raw memory area associated with this state,
+    /// new threads are initialized with a copy of the main thread's area
+    #[inline(always)]
+    pub fn extra_space(&self) -> *mut c_void {
+        unsafe { lua_getextraspace(self.0) }
+    }
+
+    /// Stores a pointer in the extra space, not available with the `thread` feature on a vendored lua,
+    /// whose lock uses the extra space itself
+    #[cfg(not(all(feature = "thread", feature = "vendored")))]
+    #[inline(always)]
+    pub fn set_extra<T>(&self, data: *mut T) {
+        unsafe { *self.extra_space().cast::<*mut T>() = data }
+    }
+
+    /// Gets the pointer stored by [`State::set_extra`]
+    #[cfg(not(all(feature = "thread", feature = "vendored")))]
+    #[inline(always)]
+    pub fn extra<T>(&self) -> *mut T {
+        unsafe { *self.extra_space().cast::<*mut T>() }
+    }
+
+    /// [-0, +0, m] Returns an owned traceback of this state starting at `level`
+    pub fn capture_traceback(&self, level: c_int) -> String {
+        unsafe { luaL_traceback(self.0, self.0, ptr::null(), level) }
+        let result = self.to_str(-1).unwrap_or_default().to_string();
+        self.pop(1);
+        result
+    }
+
     #[inline(always)]
     pub fn balance_with<'a, T: 'a, F: FnOnce(&'a State) -> T>(&'a self, callback: F) -> T {
         let top = self.get_top();
@@ -1712,6 +2155,11 @@ impl State {
         BalanceState::new(self)
     }
 
+    #[inline(always)]
+    pub fn stack_guard(&self) -> StackGuard {
+        StackGuard::new(self)
+    }
+
     #[inline(always)]
     pub fn error_string(&self, e: impl AsRef<str>) -> ! {
         self.push_string(e.as_ref());
@@ -1738,7 +2186,12 @@ impl State {
         1
     }
 
+    /// [`Value::None`] for an absent value, e.g. an index above the top, and [`Value::Nil`] for nil
     pub fn value(&self, i: Index) -> Value {
+        // an index above the top is absent, don't read the slots out of the frame
+        if i > self.get_top() {
+            return Value::None;
+        }
         match unsafe { lua_type(self.0, i) } {
             LUA_TNONE => Value::None,
             LUA_TNIL => Value::Nil,
@@ -1756,7 +2209,7 @@ impl State {
             LUA_TFUNCTION => Value::Function,
             LUA_TUSERDATA => Value::Userdata,
             LUA_TTHREAD => Value::Thread,
-            _ => panic!(""),
+            _ => Value::None,
         }
     }
 }
@@ -1782,3 +2235,41 @@ impl Drop for BalanceState<'_> {
         self.set_top(self.top);
     }
 }
+
+/// Verifies the stack top instead of restoring it like [`BalanceState`],
+/// a mismatch at [`StackGuard::checkpoint`] or on drop panics in debug builds
+#[derive(Deref)]
+pub struct StackGuard<'a> {
+    #[deref]
+    state: &'a State,
+    pub expected: i32,
+}
+
+impl<'a> StackGuard<'a> {
+    pub fn new(state: &'a State) -> Self {
+        Self {
+            state,
+            expected: state.get_top(),
+        }
+    }
+
+    /// Asserts the stack top is the expected one
+    #[inline(always)]
+    pub fn checkpoint(&self) {
+        debug_assert_eq!(
+            self.get_top(),
+            self.expected,
+            "stack top mismatch at checkpoint"
+        );
+    }
+}
+
+impl Drop for StackGuard<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            return;
+        }
+        debug_assert_eq!(self.get_top(), self.expected, "stack top mismatch on drop");
+    }
+}
@@ -20,6 +20,11 @@ impl UserData for Test {
     fn setter(fields: &ValRef) {
         fields.register("a", |this: &mut Self, val: i32| this.a = val);
     }
+
+    fn index_fallback(&self, s: &State, key: ValRef) -> Option<Pushed> {
+        let key = s.to_str(key.index)?;
+        key.starts_with("dyn_").then(|| s.pushed(self.a))
+    }
 }
 
 impl UserData for Rc<Test> {
@@ -59,6 +64,8 @@ fn userdata() {
     s.do_string("assert(uv.a == 0)").unwrap();
     s.do_string("uv:inc(); assert(uv.a == 1)").unwrap();
     s.do_string("uv.a = 3; assert(uv.a == 3)").unwrap();
+    s.do_string("assert(uv.dyn_a == 3 and uv.b == nil)")
+        .unwrap();
 
     let test = Rc::new(Test { a: 123 });
     s.global().set("uv", test.clone());
@@ -66,6 +73,37 @@ fn userdata() {
     s.do_string("print(uv, uv1)");
     s.do_string("assert(uv == uv1)").unwrap();
     s.do_string("assert(uv.a == 123)").unwrap();
+    let shared = s.global().getopt::<_, Rc<Test>>("uv").unwrap();
+    assert!(Rc::ptr_eq(&shared, &test));
+}
+
+#[test]
+fn vec_handle() {
+    let s = State::new();
+    s.open_base();
+    s.global().set("v", VecHandle(vec![1i64, 2, 3]));
+    s.do_string(
+        r#"
+        assert(#v == 3 and v[1] == 1 and v[4] == nil)
+        v[1] = 10
+        v[4] = 4
+        v:push(5)
+        assert(v:pop() == 5)
+        assert(not pcall(function() v[10] = 0 end))
+    "#,
+    )
+    .unwrap();
+
+    let v = s.global().getopt::<_, &VecHandle<i64>>("v").unwrap();
+    assert_eq!(v.0, [10, 2, 3, 4]);
+
+    // the converted value is released when the index is out of range
+    let elem = Rc::new(Test { a: 1 });
+    s.global().set("elem", elem.clone());
+    s.global().set("w", VecHandle(Vec::<Rc<Test>>::new()));
+    s.do_string("assert(not pcall(function() w[2] = elem end))")
+        .unwrap();
+    assert_eq!(Rc::strong_count(&elem), 2);
 }
 
 #[test]
@@ -73,8 +111,8 @@ fn serde() {
     use ::serde::{Deserialize, Serialize};
 
     #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
-    struct Test<'a> {
-        str: &'a str,
+    struct Test {
+        str: String,
         int: i32,
         flt: f64,
     }
@@ -83,7 +121,7 @@ fn serde() {
     s.open_base();
     let global = s.global();
     let test = Test {
-        str: "abc",
+        str: "abc".into(),
         int: 333,
         flt: 123.0,
     };
@@ -96,6 +134,26 @@ fn serde() {
     let t = global.getopt::<_, CRegVal>("test").unwrap();
     global.set("test", SerdeValue(t));
     s.do_string("print('regval', test)").unwrap();
+
+    s.set_top(0);
+    let global = s.global();
+    s.do_string("test = {f = print, list = {print}}").unwrap();
+    let t = global.get("test");
+    // a coroutine has a separate stack but shares the registry
+    let co = Coroutine::empty(&s);
+    assert!(co.push_serialize(t).is_err());
+    co.set_top(0);
+
+    let options = SerializeOptions {
+        handles: true,
+        ..Default::default()
+    };
+    co.push_serialize(t.serialize_with(options)).unwrap();
+    co.restore_handles(-1);
+    co.xmove(&s, 1);
+    global.set("copy", TopVal);
+    s.do_string("assert(copy.f == print and copy.list[1] == print)")
+        .unwrap();
 }
 
 #[test]
@@ -145,4 +203,1541 @@ fn test_thread() {
     s.open_libs();
     s.init_llua_global();
     s.do_file("tests/thread.lua").unwrap();
+
+    s.do_string(
+        r#"
+        local t = thread.spawn(function()
+            return { 1, 2.5, 'x', true }, { map = { k = 'v' }, seq = { { n = 1 } } }
+        end)
+        local seq, map = t:join()
+        assert(seq[1] == 1 and math.type(seq[1]) == 'integer' and seq[2] == 2.5)
+        assert(seq[3] == 'x' and seq[4] == true and #seq == 4)
+        assert(map.map.k == 'v' and map.seq[1].n == 1)
+
+        t = thread.spawn(function() return 1, print end)
+        local ok, err = pcall(t.join, t)
+        assert(not ok and err:find('return value #2 is not serializable: function', 1, true), err)
+    "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn error_popped() {
+    let s = State::new();
+    for _ in 0..1000 {
+        assert!(s.do_string("syntax error(").is_err());
+    }
+    assert_eq!(s.get_top(), 0);
+}
+
+#[test]
+fn args_checked() {
+    let s = State::new();
+    s.open_libs();
+    s.global().set(
+        "f",
+        RsFn::new(|s: &State| {
+            let err = s.args_checked::<(i64, &str, i64, f64)>().unwrap_err();
+            assert_eq!(err.0.iter().map(|m| m.index).collect::<Vec<_>>(), [1, 3]);
+            err.to_string()
+        }),
+    );
+    s.do_string(
+        r#"
+        local msg = f({}, 'a', 'b', 2.0)
+        assert(msg:find('bad argument #1') and msg:find('bad argument #3'))
+    "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn iter_vec_large() {
+    let s = State::new();
+    s.push(IterVec(0..10000));
+    assert_eq!(s.raw_len(-1), 10000);
+    s.push(IterMap((1..=10000).map(|i| (i, i))));
+    assert_eq!(s.raw_len(-1), 10000);
+    assert_eq!(s.get_top(), 2);
+
+    // a new state guarantees LUA_MINSTACK free slots, fill all but one of them,
+    // the nested conversions need more slots than are left
+    s.set_top(0);
+    for _ in 0..ffi::LUA_MINSTACK - 1 {
+        s.push_nil();
+    }
+    s.push(IterVec(
+        (0..3).map(|i| IterMap([(i, IterVec(0..i))].into_iter())),
+    ));
+    assert_eq!(s.raw_len(-1), 3);
+}
+
+#[test]
+fn gc_mode() {
+    let s = State::new();
+    let params = GcParams {
+        minormul: 20,
+        ..Default::default()
+    };
+    assert_eq!(
+        s.gc_set_mode(GcMode::Generational, params),
+        GcMode::Incremental
+    );
+    assert_eq!(
+        s.gc_set_mode(GcMode::Incremental, GcParams::default()),
+        GcMode::Generational
+    );
+    assert_eq!(s.gc(GcOption::Generational, 0), ffi::LUA_GCINC);
+    assert_eq!(s.gc(GcOption::Incremental, 0), ffi::LUA_GCGEN);
+    assert_eq!(s.gc(GcOption::Incremental, 0), ffi::LUA_GCINC);
+}
+
+#[test]
+fn collect_full() {
+    let s = State::new();
+    s.open_base();
+    s.gc(GcOption::Stop, 0);
+    s.do_string(
+        r#"
+        finalized = 0
+        kept = setmetatable({}, {__gc = function() finalized = finalized + 1 end})
+        setmetatable({}, {__gc = function() finalized = finalized + 1 end})
+    "#,
+    )
+    .unwrap();
+    s.collect_full();
+    assert_eq!(s.global().getopt::<_, i64>("finalized"), Some(1));
+    s.do_string("kept = nil").unwrap();
+    s.collect_full();
+    assert_eq!(s.global().getopt::<_, i64>("finalized"), Some(2));
+}
+
+#[test]
+fn nonzero_integer() {
+    use core::num::{NonZeroI64, NonZeroU32};
+
+    let s = State::new();
+    s.push(NonZeroU32::new(7).unwrap());
+    assert_eq!(s.arg::<NonZeroU32>(-1), NonZeroU32::new(7));
+    assert_eq!(s.arg::<NonZeroI64>(-1), NonZeroI64::new(7));
+    for v in ["0", "0x100000000", "1.5", "'1'"] {
+        s.do_string(&alloc::format!("v = {v}")).unwrap();
+        assert_eq!(s.global().getopt::<_, NonZeroU32>("v"), None, "{v}");
+    }
+}
+
+#[test]
+fn error_object() {
+    #[derive(Debug, ::serde::Serialize)]
+    struct Failure {
+        code: i32,
+        reason: &'static str,
+    }
+
+    let s = State::new();
+    s.open_base();
+    s.global().set(
+        "check",
+        RsFn::new(|n: i32| -> Result<i32, ErrorObject<Failure>> {
+            if n < 0 {
+                Err(Failure {
+                    code: 42,
+                    reason: "negative",
+                }
+                .into())
+            } else {
+                Ok(n)
+            }
+        }),
+    );
+    s.do_string(
+        r#"
+        assert(check(1) == 1)
+        local ok, err = pcall(check, -1)
+        assert(not ok and err.code == 42 and err.reason == 'negative')
+    "#,
+    )
+    .unwrap();
+}
+
+#[cfg(feature = "indexmap")]
+#[test]
+fn indexmap() {
+    use indexmap::IndexMap;
+
+    let s = State::new();
+    s.do_string("t = {[10] = 'a', x = 'b'}").unwrap();
+    assert!(s.global().getopt::<_, IndexMap<i64, String>>("t").is_none());
+    // number keys are converted to strings without breaking the traversal
+    let t = s
+        .global()
+        .getopt::<_, IndexMap<String, String>>("t")
+        .unwrap();
+    assert_eq!(t.len(), 2);
+    assert_eq!(t["10"], "a");
+    s.do_string("t = {[10] = 'a', [20] = 'b', [-3] = 'c'}")
+        .unwrap();
+    let t = s.global().getopt::<_, IndexMap<i64, String>>("t").unwrap();
+    assert_eq!(t.len(), 3);
+    assert_eq!(t[&20], "b");
+    let g = s.global().get("t");
+    s.push_nil();
+    let mut order = vec![];
+    while s.next(g.index) {
+        order.push(s.to_integer(-2));
+        s.pop(1);
+    }
+    assert_eq!(t.keys().copied().collect::<Vec<_>>(), order);
+}
+
+#[test]
+fn run_isolated() {
+    let s = State::new();
+    s.open_libs();
+    let top = s.get_top();
+    s.do_string("shared = 1").unwrap();
+
+    let r = s.run_isolated("shared = 2; x = 'a'; return math.max(shared, 1)", "a");
+    assert_eq!(r.unwrap().cast::<i64>(), Some(2));
+    s.pop(1);
+    s.run_isolated("assert(shared == nil and x == nil); _G.shared = 3", "b")
+        .unwrap();
+    s.pop(1);
+    // only the safe globals are reachable, and the libraries are copies
+    s.run_isolated(
+        r#"
+        assert(os == nil and io == nil and load == nil and require == nil and debug == nil)
+        assert(dofile == nil and loadfile == nil and collectgarbage == nil and package == nil)
+        assert(print and pairs and string.format and table.concat and math.max and utf8.char)
+        string.format = nil
+    "#,
+        "c",
+    )
+    .unwrap();
+    s.pop(1);
+    s.do_string("assert(string.format)").unwrap();
+    match s.run_isolated("error('oops')", "plugin") {
+        Err(crate::error::Error::Runtime(err)) => assert!(err.starts_with("plugin:1:")),
+        _ => panic!("expected a runtime error"),
+    }
+    assert_eq!(s.get_top(), top);
+    assert_eq!(s.global().getopt::<_, i64>("shared"), Some(1));
+}
+
+#[test]
+fn stack_frames() {
+    let s = State::new();
+    s.open_base();
+    s.global().set(
+        "frames",
+        RsFn::new(|s: &State| {
+            s.stack_frames()
+                .map(|f| alloc::format!("{}:{}", f.name.unwrap_or_default(), f.current_line))
+                .collect::<Vec<_>>()
+                .join(",")
+        }),
+    );
+    s.pop(1);
+    s.load_named(
+        "local function foo()\n  return (frames())\nend\nreturn (foo())",
+        "chunk",
+    )
+    .unwrap();
+    s.pcall(0, 1, 0);
+    assert_eq!(s.to_str(-1), Some("frames:-1,foo:2,:4"));
+}
+
+#[test]
+fn status_into_result() {
+    let s = State::new();
+    s.open_base();
+    let top = s.get_top();
+    s.load_string("error('bad')").unwrap();
+    match s.pcall(0, 0, 0).into_result(&s) {
+        Err(crate::error::Error::Runtime(err)) => assert!(err.ends_with("bad")),
+        _ => panic!("expected a runtime error"),
+    }
+    assert_eq!(s.get_top(), top);
+    assert!(ThreadStatus::Yield.into_result(&s).is_ok());
+}
+
+#[test]
+fn pack_unpack() {
+    let s = State::new();
+    s.open_libs();
+    let top = s.get_top();
+    let data = s.pack("<i4z", (0x11223344, "ab")).unwrap();
+    assert_eq!(data, b"\x44\x33\x22\x11ab\0");
+    let (n, z): (i64, String) = s.unpack("<i4z", &data).unwrap();
+    assert_eq!((n, z.as_str()), (0x11223344, "ab"));
+    assert_eq!(s.unpack::<i64>("<i4", &data).unwrap(), 0x11223344);
+    // the trailing position isn't taken as a value when the format is short
+    assert!(matches!(
+        s.unpack::<(i64, i64)>("<i4", &data),
+        Err(crate::error::Error::ConvertFailed)
+    ));
+    assert!(s.unpack::<i64>("<i4z", &data).is_err());
+    assert!(s.pack("i4", "x").is_err());
+    assert_eq!(s.get_top(), top);
+}
+
+#[test]
+fn push_static_str() {
+    const NAME: &str = "method_name";
+    let s = State::new();
+    s.push_static_str(NAME);
+    s.push_static_str(NAME);
+    s.push_static_str(&NAME[..6]);
+    assert_eq!(s.to_str(1), Some(NAME));
+    assert!(s.raw_equal(1, 2));
+    assert_eq!(s.to_str(3), Some("method"));
+    assert_eq!(s.get_top(), 3);
+}
+
+#[test]
+fn vec_from_table() {
+    let s = State::new();
+    s.open_base();
+    s.do_string("t = {1, 2, 3, nil, 5}; bad = {1, 'x'}; s = 'abc'")
+        .unwrap();
+    let g = s.global();
+    assert_eq!(g.getopt::<_, Vec<i64>>("t"), Some(vec![1, 2, 3]));
+    assert_eq!(g.getopt::<_, Vec<i64>>("bad"), None);
+    assert_eq!(g.getopt::<_, Vec<u8>>("s"), Some(b"abc".to_vec()));
+}
+
+#[cfg(feature = "compat")]
+#[test]
+fn compat_lua() {
+    let lua = compat::Lua::new();
+    lua.globals()
+        .set("add", lua.create_function(|a: i64, b: i64| a + b));
+    lua.load("x = add(1, 2)").set_name("init").exec().unwrap();
+    assert_eq!(lua.load("x * 2").eval::<i64>().unwrap(), 6);
+    assert_eq!(lua.load("return ... + x").call::<_, i64>(4).unwrap(), 7);
+    lua.set_named_registry_value("key", "v").unwrap();
+    assert_eq!(
+        lua.named_registry_value::<String>("key").as_deref(),
+        Some("v")
+    );
+    assert!(lua.load("error('e')").set_name("init").exec().is_err());
+    assert_eq!(lua.get_top(), 2);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "invalid stack index -2")]
+fn val_invalid_index() {
+    let s = State::new();
+    s.push(1);
+    s.val(-2);
+}
+
+#[test]
+fn readonly_table() {
+    let s = State::new();
+    s.open_libs();
+    s.do_string("config = {name = 'a', 1, 2}").unwrap();
+    s.global().get("config").make_readonly();
+    s.do_string(
+        r#"
+        assert(config.name == 'a' and #config == 2)
+        local n = 0
+        for k, v in pairs(config) do n = n + 1 end
+        assert(n == 3)
+        assert(not pcall(function() config.name = 'b' end))
+        assert(not pcall(function() config.new = 1 end))
+        assert(config.name == 'a' and config.new == nil)
+        assert(getmetatable(config) == 'read-only table')
+        assert(not pcall(setmetatable, config, {}))
+    "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn call_multi() {
+    let s = State::new();
+    s.open_base();
+    let top = s.get_top();
+    s.load_string("return function(n, ...) return select(n, ...) end")
+        .unwrap();
+    s.pcall(0, 1, 0);
+    let f = s.val(-1);
+    assert_eq!(f.call_multi::<_, i64>((2, 1, 2, 3)).unwrap(), [2, 3]);
+    assert!(f.call_multi::<_, i64>((4, 1)).unwrap().is_empty());
+    assert!(f.call_multi::<_, i64>(("x", 1)).is_err());
+    assert_eq!(s.get_top(), top + 1);
+}
+
+#[test]
+fn absent_value() {
+    let s = State::new();
+    s.push_nil();
+    assert!(matches!(s.value(1), Value::Nil));
+    assert!(matches!(s.value(2), Value::None));
+    assert!(matches!(s.value(100), Value::None));
+    assert_eq!(s.val(1).deserialize::<Option<i64>>().unwrap(), None);
+    assert_eq!(s.val(1).deserialize::<()>().unwrap(), ());
+}
+
+#[test]
+fn metatable_builder() {
+    struct Point(i64, i64);
+
+    let s = State::new();
+    s.open_base();
+    let init = MetatableBuilder::new(&s)
+        .getter("x", |s: &State| {
+            s.registered_userdata::<Point>(1).map(|p| p.0)
+        })
+        .method("move", |s: &State, _this: AnyVal, dx: i64, dy: i64| {
+            let p = s.registered_userdata::<Point>(1).unwrap();
+            p.0 += dx;
+            p.1 += dy;
+        })
+        .meta("__tostring", |s: &State| {
+            let p = s.registered_userdata::<Point>(1).unwrap();
+            alloc::format!("({}, {})", p.0, p.1)
+        })
+        .register::<Point>();
+    let top = s.get_top();
+    s.push_userdata(Point(1, 2), Some(init));
+    s.global().set("p", s.val(-2));
+    s.set_top(top);
+    s.do_string("p:move(1, 1); assert(p.x == 2 and tostring(p) == '(2, 3)')")
+        .unwrap();
+    s.do_string("assert(p.y == nil)").unwrap();
+}
+
+#[test]
+fn register_boxed() {
+    let s = State::new();
+    s.open_base();
+    let top = s.get_top();
+    for (name, n) in [("one", 1i64), ("two", 2)] {
+        s.register_boxed(
+            name,
+            Box::new(move |s: &State| {
+                s.push(n + s.arg::<i64>(1).unwrap_or(0));
+                1
+            }),
+        );
+    }
+    assert_eq!(s.get_top(), top);
+    s.do_string("assert(one() == 1 and two(10) == 12)").unwrap();
+}
+
+#[test]
+fn yield_values() {
+    let s = State::new();
+    s.open_libs();
+    s.register_boxed(
+        "double",
+        Box::new(|s: &State| -> i32 { s.yield_values("ready", |_, x: i64| x * 2) }),
+    );
+    s.do_string(
+        r#"
+        local co = coroutine.wrap(function() return double() end)
+        assert(co() == 'ready')
+        assert(co(21) == 42)
+    "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn byte_view() {
+    let s = State::new();
+    s.open_libs();
+    let top = s.get_top();
+    // strings have a metatable once the string lib is opened
+    s.push("abc");
+    assert_eq!(s.arg::<&[u8]>(-1), Some(&b"abc"[..]));
+    assert_eq!(s.get_top(), top + 1);
+
+    s.push_bytes_zero_copy(b"xyz".to_vec());
+    assert_eq!(s.arg::<&[u8]>(-1), Some(&b"xyz"[..]));
+    assert_eq!(s.get_top(), top + 2);
+
+    let g = s.global();
+    g.set("k", "value");
+    assert_eq!(g.getopt::<_, &[u8]>("k"), Some(&b"value"[..]));
+    g.set("v", ByteView::from(b"xyz".to_vec()));
+    assert_eq!(g.getopt::<_, &[u8]>("v"), Some(&b"xyz"[..]));
+    s.do_string("assert(#v == 3 and v[1] == 120 and v[4] == nil and v:to_string() == 'xyz')")
+        .unwrap();
+}
+
+#[test]
+fn scoped_fn() {
+    let s = State::new();
+    s.open_base();
+    let mut count = 0;
+    let top = s.get_top();
+    let index = s.scoped_fn(
+        |s| {
+            count += s.arg::<i32>(1).unwrap_or(1);
+            0
+        },
+        |func| {
+            s.global().set("add", &**func);
+            s.do_string("add(); add(10)").unwrap();
+            func.index
+        },
+    );
+    assert_eq!(index, top + 1);
+    assert_eq!(count, 11);
+    let err = s.do_string("add()").unwrap_err();
+    assert!(alloc::format!("{err:?}").contains("expired"));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn hash_set() {
+    use std::collections::HashSet;
+
+    let s = State::new();
+    let ints = HashSet::<i64>::from([1, 2, 3]);
+    s.push(ints.clone());
+    assert_eq!(s.arg::<HashSet<i64>>(-1), Some(ints));
+    let names = HashSet::from(["read".to_string(), "write".to_string()]);
+    s.push(names.clone());
+    assert_eq!(s.arg::<HashSet<String>>(-1), Some(names.clone()));
+
+    s.do_string("array = {'read', 'write', 'read'}; map = {read = true, write = 1, exec = false}")
+        .unwrap();
+    let g = s.global();
+    assert_eq!(g.getopt::<_, HashSet<String>>("array"), Some(names.clone()));
+    assert_eq!(g.getopt::<_, HashSet<String>>("map"), Some(names));
+}
+
+#[test]
+fn app_data() {
+    // the crate root glob imports `Borrow`, which shadows the methods of `RefCell` through `Rc`
+    use core::cell::RefCell;
+
+    struct Context {
+        calls: i32,
+    }
+
+    let s = State::new();
+    s.open_base();
+    assert!(s.app_data::<Context>().is_none());
+    s.set_app_data(Context { calls: 0 });
+    s.set_app_data(1u8);
+    s.global().set(
+        "call",
+        RsFn::new(|s: &State| {
+            let ctx = s.app_data::<Context>().unwrap();
+            RefCell::borrow_mut(&ctx).calls += 1;
+        }),
+    );
+    s.do_string("call(); call()").unwrap();
+    assert_eq!(RefCell::borrow(&s.app_data::<Context>().unwrap()).calls, 2);
+
+    // the value got before is still valid after being replaced through another handle
+    let old = s.app_data::<u8>().unwrap();
+    let co = s.new_thread();
+    co.set_app_data(2u8);
+    assert_eq!(*RefCell::borrow(&old), 1);
+    assert_eq!(*RefCell::borrow(&s.app_data::<u8>().unwrap()), 2);
+}
+
+#[test]
+fn invalid_table_key() {
+    struct FloatKeys(f64);
+
+    impl ::serde::Serialize for FloatKeys {
+        fn serialize<S: ::serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            s.collect_map([(1.5, 1), (self.0, 2)])
+        }
+    }
+
+    let s = State::new();
+    s.open_base();
+    let top = s.get_top();
+    for key in [f64::NAN, f64::INFINITY] {
+        let err = s.push_serialize(FloatKeys(key)).unwrap_err();
+        assert_eq!(
+            err,
+            SerErr::InvalidKey(alloc::format!("invalid table key: {key}"))
+        );
+        s.set_top(top);
+    }
+    s.push_serialize(FloatKeys(2.5)).unwrap();
+    s.set_top(top);
+
+    s.global().set(
+        "float_keys",
+        RsFn::new(|key: f64| IterMap([(key, true)].into_iter())),
+    );
+    s.do_string("assert(float_keys(1.5)[1.5])").unwrap();
+    for key in ["0/0", "1/0", "-1/0"] {
+        let err = s
+            .do_string(&alloc::format!("float_keys({key})"))
+            .unwrap_err();
+        assert!(
+            alloc::format!("{err:?}").contains("invalid table key"),
+            "{err:?}"
+        );
+    }
+
+    // the iterator is dropped before raising the error
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    s.global().set(
+        "nan_key",
+        RsFn::new(|| {
+            let guard = Guard;
+            IterMap([(f64::NAN, true)].into_iter().map(move |kv| {
+                let _ = &guard;
+                kv
+            }))
+        }),
+    );
+    assert!(s.do_string("nan_key()").is_err());
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn intern_userdata() {
+    let s = State::new();
+    let (a, b) = (1u8, 2u8);
+    let key = |k: &u8| k as *const u8 as *const ();
+    let mut made = 0;
+    let first = s.intern_userdata(key(&a), || {
+        made += 1;
+        Test { a: 1 }
+    });
+    let again = s.intern_userdata(key(&a), || {
+        made += 1;
+        Test { a: 2 }
+    });
+    assert!(s.raw_equal(first.index, again.index));
+    assert_eq!(made, 1);
+    let other = s.intern_userdata(key(&b), || Test { a: 3 });
+    assert!(!s.raw_equal(first.index, other.index));
+    let uncached = s.intern_userdata(core::ptr::null(), || Test { a: 4 });
+    assert!(!s.raw_equal(first.index, uncached.index));
+    assert_eq!(s.arg::<&Test>(again.index).map(|t| t.a), Some(1));
+}
+
+#[test]
+fn push_result_with() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct DropErr;
+    impl core::fmt::Display for DropErr {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.write_str("dropped")
+        }
+    }
+    impl Drop for DropErr {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let s = State::new();
+    s.open_base();
+    s.global().set(
+        "raise",
+        RsFn::new(|s: &State| Pushed(s.push_result_with(Err::<i32, _>(DropErr), ErrorMode::Raise))),
+    );
+    let err = s.do_string("raise()").unwrap_err();
+    assert!(alloc::format!("{err:?}").contains("dropped"));
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn box_result_iter() {
+    let s = State::new();
+    s.open_base();
+    let items = || [Ok(1), Err("broken"), Ok(3)].into_iter();
+    let g = s.global();
+    g.set("raise", BoxResultIter::new(items()));
+    g.set(
+        "nil_err",
+        BoxResultIter::with_mode(items(), ErrorMode::ReturnNilErr),
+    );
+    g.set(
+        "false_err",
+        BoxResultIter::with_mode(items(), ErrorMode::ReturnFalseErr),
+    );
+
+    let err = s.do_string("for v in raise do end").unwrap_err();
+    assert!(alloc::format!("{err:?}").contains("broken"));
+    s.do_string(
+        r#"
+        local seen = {}
+        for v in nil_err do seen[#seen + 1] = v end
+        -- the error ends the loop silently, but the iterator can be resumed
+        assert(#seen == 1 and nil_err() == 3)
+
+        seen = {}
+        for v, err in false_err do seen[#seen + 1] = err or v end
+        assert(#seen == 3 and seen[2] == 'broken' and seen[3] == 3)
+    "#,
+    )
+    .unwrap();
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn sort_keys() {
+    let s = State::new();
+    s.do_string("t = {b = 1, [10] = 2, a = 3, [2] = 4, [1.5] = 5, [-1] = 6, ab = 7}")
+        .unwrap();
+    let options = SerializeOptions {
+        sort_keys: true,
+        ..Default::default()
+    };
+    let yaml = serde_yaml::to_string(&s.global().get("t").serialize_with(options)).unwrap();
+    assert_eq!(yaml, "-1: 6\n1.5: 5\n2: 4\n10: 2\na: 3\nab: 7\nb: 1\n");
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn encoding() {
+    use crate::binding::std::encoding::*;
+
+    for (data, b64) in [
+        (&b""[..], ""),
+        (b"f", "Zg=="),
+        (b"fo", "Zm8="),
+        (b"foo", "Zm9v"),
+        (b"foob", "Zm9vYg=="),
+        (b"\xff\x00\xfe", "/wD+"),
+    ] {
+        assert_eq!(base64_encode(data), b64);
+        assert_eq!(base64_decode(b64.as_bytes()).unwrap(), data);
+        // the padding is optional
+        assert_eq!(
+            base64_decode(b64.trim_end_matches('=').as_bytes()).unwrap(),
+            data
+        );
+    }
+    assert_eq!(base64_decode(b"Zm9vY"), Err("invalid base64 length"));
+    assert_eq!(base64_decode(b"Zm9v!A=="), Err("invalid base64 character"));
+    assert_eq!(base64_decode(b"Zg=a"), Err("invalid base64 character"));
+
+    assert_eq!(hex_encode(b"\x00\x7f\xab"), "007fab");
+    assert_eq!(hex_decode(b"007fAB").unwrap(), b"\x00\x7f\xab");
+    assert_eq!(hex_decode(b"abc"), Err("invalid hex length"));
+    assert_eq!(hex_decode(b"0g"), Err("invalid hex character"));
+
+    let s = State::new();
+    s.open_libs();
+    s.init_llua_global();
+    s.do_string(
+        r#"
+        local data = string.rep('\0\1\254\255 llua', 10)
+        assert(base64.decode(base64.encode(data)) == data)
+        assert(hex.decode(hex.encode(data)) == data)
+        assert(not pcall(hex.decode, 'zz'))
+    "#,
+    )
+    .unwrap();
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn toml_binding() {
+    let s = State::new();
+    s.open_libs();
+    s.init_llua_global();
+    s.do_string(
+        r#"
+        local toml = require 'toml'
+        local t = {name = 'llua', list = {1, 2.5, 'x'}, ports = {[443] = 'https', [80] = 'http'}}
+        text = toml.encode(t)
+        local d = toml.decode(text)
+        assert(d.name == 'llua' and d.list[2] == 2.5 and d.list[3] == 'x')
+        -- the number keys are encoded as strings
+        assert(d.ports['80'] == 'http' and d.ports['443'] == 'https')
+        assert(not pcall(toml.decode, 'a = '))
+    "#,
+    )
+    .unwrap();
+    assert_eq!(
+        s.global().getopt::<_, String>("text").unwrap(),
+        "list = [1, 2.5, \"x\"]\nname = \"llua\"\n\n[ports]\n80 = \"http\"\n443 = \"https\"\n"
+    );
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn yaml_binding() {
+    let s = State::new();
+    s.open_libs();
+    s.init_llua_global();
+    s.do_string(
+        r#"
+        local yaml = require 'yaml'
+        local t = {name = 'llua', list = {1, 2.5, 'x'}, ports = {[443] = 'https', [80] = 'http'}}
+        text = yaml.encode(t)
+        local d = yaml.decode(text)
+        assert(d.name == 'llua' and d.list[2] == 2.5 and d.list[3] == 'x')
+        assert(d.ports[80] == 'http' and d.ports[443] == 'https')
+        assert(yaml.decode('[1, {a: true}]')[2].a == true)
+    "#,
+    )
+    .unwrap();
+    assert_eq!(
+        s.global().getopt::<_, String>("text").unwrap(),
+        "list:\n- 1\n- 2.5\n- x\nname: llua\nports:\n  80: http\n  443: https\n"
+    );
+}
+
+#[cfg(feature = "hashing")]
+#[test]
+fn hash_binding() {
+    let s = State::new();
+    s.open_libs();
+    s.init_llua_global();
+    s.do_string(
+        r#"
+        assert(hash.sha256('') == 'e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855')
+        assert(hash.sha256('abc') == 'ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad')
+        assert(hash.md5('') == 'd41d8cd98f00b204e9800998ecf8427e')
+        assert(hash.md5('abc') == '900150983cd24fb0d6963f7d28e17f72')
+        assert(hash.crc32('') == 0)
+        assert(hash.crc32('123456789') == 0xcbf43926)
+    "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn sort_table() {
+    let s = State::new();
+    s.open_libs();
+    s.do_string(
+        r#"
+        list = {}
+        for i = 1, 100 do list[i] = {key = (i * 37) % 10, id = i} end
+        function less(a, b) return a.key < b.key end
+    "#,
+    )
+    .unwrap();
+    let list = s.global().get("list");
+    let less = s.global().get("less");
+    let top = s.get_top();
+    let ids = || {
+        (1..=100)
+            .map(|i| {
+                let id = list.geti(i).get("id").cast::<i64>().unwrap();
+                s.pop(2);
+                id
+            })
+            .collect::<Vec<_>>()
+    };
+    let before = ids();
+
+    // the first error stops the sorting, and the table is left as it was
+    let mut count = 0;
+    let err = s.sort_table(list.index, |a, b| {
+        count += 1;
+        if count == 50 {
+            return Err(crate::error::Error::runtime("cmp error"));
+        }
+        let result = a.get("key").cast::<i64>().cmp(&b.get("key").cast::<i64>());
+        s.pop(2);
+        Ok(result)
+    });
+    assert!(matches!(err, Err(crate::error::Error::Runtime(e)) if e == "cmp error"));
+    assert_eq!(count, 50);
+    assert_eq!(ids(), before);
+
+    // the comparator calls into lua, and the sorting is stable
+    s.sort_table(list.index, |a, b| {
+        if less.call_multi::<_, bool>((a, b))? == [true] {
+            Ok(core::cmp::Ordering::Less)
+        } else if less.call_multi::<_, bool>((b, a))? == [true] {
+            Ok(core::cmp::Ordering::Greater)
+        } else {
+            Ok(core::cmp::Ordering::Equal)
+        }
+    })
+    .unwrap();
+    let mut expected = before;
+    expected.sort_by_key(|i| (i * 37) % 10);
+    assert_eq!(ids(), expected);
+    assert_eq!(s.get_top(), top);
+
+    s.push("not a table");
+    let err = s.sort_table(-1, |_, _| Ok(core::cmp::Ordering::Equal));
+    assert!(
+        matches!(err, Err(crate::error::Error::Runtime(e)) if e == "table expected, got string")
+    );
+    s.set_top(top);
+}
+
+#[test]
+fn gc_pressure() {
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static LINES: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn line_hook(_: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+        LINES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let s = State::new();
+    s.open_libs();
+    s.collect_full();
+    let used = s.memory_used();
+    assert!(used > 0);
+    s.do_string("big = {} for i = 1, 10000 do big[i] = {} end")
+        .unwrap();
+    assert!(s.memory_used() > used + 10000 * 32);
+
+    // the hook set before is still called
+    unsafe { ffi::lua_sethook(s.as_ptr(), Some(line_hook), ffi::LUA_MASKLINE, 0) };
+    let count = Rc::new(Cell::new(0));
+    let c = count.clone();
+    s.on_gc_pressure(s.memory_used() + 1024 * 1024, move |_| c.set(c.get() + 1));
+    s.do_string("local t = {} for i = 1, 10 do t[i] = 0 end")
+        .unwrap();
+    assert_eq!(count.get(), 0);
+    assert!(LINES.load(Ordering::Relaxed) > 0);
+
+    // called once when the memory grows over the threshold, and again after it dropped
+    s.do_string("t = {} for i = 1, 100000 do t[i] = {} end")
+        .unwrap();
+    assert_eq!(count.get(), 1);
+    s.do_string("t = {} for i = 1, 100000 do t[i] = {} end")
+        .unwrap();
+    assert_eq!(count.get(), 1);
+    s.do_string("t = nil big = nil collectgarbage() collectgarbage() for i = 1, 1000 do end")
+        .unwrap();
+    s.do_string("t = {} for i = 1, 100000 do t[i] = {} end")
+        .unwrap();
+    assert_eq!(count.get(), 2);
+
+    assert_eq!(s.get_hook_count(), 1000);
+    assert_eq!(
+        unsafe { ffi::lua_gethookmask(s.as_ptr()) },
+        ffi::LUA_MASKLINE | ffi::LUA_MASKCOUNT
+    );
+
+    // replacing the callback keeps chaining to the line hook
+    s.on_gc_pressure(usize::MAX, |_| unreachable!());
+    let lines = LINES.load(Ordering::Relaxed);
+    s.do_string("t = {} for i = 1, 100000 do t[i] = {} end")
+        .unwrap();
+    assert!(LINES.load(Ordering::Relaxed) > lines);
+    assert_eq!(count.get(), 2);
+    assert_eq!(
+        unsafe { ffi::lua_gethookmask(s.as_ptr()) },
+        ffi::LUA_MASKLINE | ffi::LUA_MASKCOUNT
+    );
+}
+
+#[test]
+fn call_method() {
+    let s = State::new();
+    s.open_base();
+    s.do_string(
+        r#"
+        local Counter = {}
+        Counter.__index = Counter
+        function Counter:add(n) self.n = self.n + n return self.n, self == obj end
+        function Counter:fail() error('failed') end
+        obj = setmetatable({n = 1}, Counter)
+        broken = setmetatable({}, {__index = function() error('no index') end})
+        number = 1
+    "#,
+    )
+    .unwrap();
+    let obj = s.global().get("obj");
+    let broken = s.global().get("broken");
+    let number = s.global().get("number");
+    let top = s.get_top();
+
+    // the method is looked up through __index, and self is passed as the first argument
+    assert_eq!(obj.call_method::<_, i64>("add", 2).unwrap(), 3);
+    assert_eq!(
+        obj.call_method::<_, (i64, bool)>("add", 4).unwrap(),
+        (7, true)
+    );
+    assert_eq!(obj.getopt::<_, i64>("n"), Some(7));
+
+    assert!(matches!(
+        obj.call_method::<_, ()>("missing", ()),
+        Err(crate::error::Error::Runtime(e)) if e.starts_with("method 'missing' not found")
+    ));
+    assert!(matches!(
+        obj.call_method::<_, ()>("fail", ()),
+        Err(crate::error::Error::Runtime(e)) if e.contains("failed")
+    ));
+    // the errors raised by the lookup are returned
+    assert!(matches!(
+        broken.call_method::<_, ()>("add", 1),
+        Err(crate::error::Error::Runtime(e)) if e.contains("no index")
+    ));
+    assert!(number.call_method::<_, ()>("add", 1).is_err());
+    assert_eq!(s.get_top(), top);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn error_chain() {
+    use std::{fmt, io};
+
+    #[derive(Debug)]
+    struct LoadError(io::Error);
+
+    impl fmt::Display for LoadError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "failed to load config")
+        }
+    }
+
+    impl std::error::Error for LoadError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    let s = State::new();
+    s.open_base();
+    s.global().set(
+        "load",
+        RsFn::new(|ok: bool| -> Result<i32, ErrorChain<LoadError>> {
+            if ok {
+                Ok(1)
+            } else {
+                Err(LoadError(io::Error::new(io::ErrorKind::NotFound, "no such file")).into())
+            }
+        }),
+    );
+    s.do_string(
+        r#"
+        assert(load(true) == 1)
+        local ok, err = pcall(load, false)
+        assert(not ok and err.message == 'failed to load config')
+        assert(err.cause.message == 'no such file' and err.cause.cause == nil)
+        assert(tostring(err) == 'failed to load config')
+    "#,
+    )
+    .unwrap();
+
+    let top = s.get_top();
+    s.push_error_chain(&io::Error::other("plain"));
+    let err = s.val(-1);
+    assert_eq!(err.getopt::<_, String>("message").as_deref(), Some("plain"));
+    assert!(err.get("cause").is_nil());
+    s.set_top(top);
+}
+
+#[test]
+fn add_searcher() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static OPENED: AtomicUsize = AtomicUsize::new(0);
+    unsafe extern "C" fn open_rustmod(l: *mut ffi::lua_State) -> libc::c_int {
+        let s = State::from_ptr(l);
+        OPENED.fetch_add(1, Ordering::Relaxed);
+        let m = s.table(0, 1);
+        m.set("name", s.arg::<&str>(1).unwrap_or_default());
+        1
+    }
+
+    let s = State::new();
+    s.open_libs();
+    s.add_searcher(|_, name| (name == "rustmod").then_some(open_rustmod as CFunction));
+    assert_eq!(OPENED.load(Ordering::Relaxed), 0);
+    s.do_string(
+        r#"
+        local m = require 'rustmod'
+        assert(m.name == 'rustmod' and require 'rustmod' == m)
+        local ok, err = pcall(require, 'missing')
+        assert(not ok and err:find("no rust module 'missing'", 1, true))
+    "#,
+    )
+    .unwrap();
+    // loaded lazily on the first require, and cached by package.loaded
+    assert_eq!(OPENED.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn bit_flags() {
+    bitflags::bitflags! {
+        struct Perm: u32 {
+            const READ = 1;
+            const WRITE = 2;
+            const EXEC = 4;
+        }
+    }
+    impl_bitflags!(Perm);
+
+    let s = State::new();
+    s.open_base();
+    s.global().set(
+        "writable",
+        RsFn::new(|p: Flags<Perm>| Flags(p.0 | Perm::WRITE)),
+    );
+    s.do_string(
+        r#"
+        assert(writable(5) == 7)
+        assert(not pcall(writable, 8))
+    "#,
+    )
+    .unwrap();
+
+    for (v, expected) in [
+        ("5", Some(Perm::READ | Perm::EXEC)),
+        ("0", Some(Perm::empty())),
+        ("8", None),
+        ("-1", None),
+        ("1.0", None),
+        ("'1'", None),
+    ] {
+        s.do_string(&alloc::format!("v = {v}")).unwrap();
+        assert_eq!(
+            s.global().getopt::<_, Flags<Perm>>("v").map(|f| f.0),
+            expected,
+            "{v}"
+        );
+    }
+}
+
+#[test]
+fn lua_match() {
+    let s = State::new();
+    // the string library isn't loaded
+    assert_eq!(s.lua_match("key=value", "(%w+)=(%w+)"), None);
+    s.open_libs();
+    let top = s.get_top();
+
+    assert_eq!(
+        s.lua_match("key=value", "(%w+)=(%w+)"),
+        Some(vec!["key".to_string(), "value".to_string()])
+    );
+    // the whole match without captures, and the position captures are converted to strings
+    assert_eq!(s.lua_match("abc123", "%d+"), Some(vec!["123".to_string()]));
+    assert_eq!(s.lua_match("abc", "()b"), Some(vec!["2".to_string()]));
+    assert_eq!(s.lua_match("abc", "%d"), None);
+    assert_eq!(s.lua_match("abc", "(%d"), None);
+
+    let pairs = s
+        .lua_gmatch("a=1, b=2, c=3", "(%w+)=(%w+)")
+        .unwrap()
+        .map(|c| (c[0].clone(), c[1].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [("a", "1"), ("b", "2"), ("c", "3")].map(|(k, v)| (k.to_string(), v.to_string()))
+    );
+    assert_eq!(s.lua_gmatch("abc", "%d").unwrap().count(), 0);
+    assert_eq!(s.get_top(), top);
+}
+
+#[test]
+fn checked_int() {
+    let s = State::new();
+    s.open_base();
+    for (v, expected) in [
+        ("255", Some(255)),
+        ("3.0", Some(3)),
+        ("300", None),
+        ("-1", None),
+        ("3.9", None),
+        ("'3'", None),
+        ("true", None),
+    ] {
+        s.do_string(&alloc::format!("v = {v}")).unwrap();
+        assert_eq!(
+            s.global().getopt::<_, Checked<u8>>("v").map(|v| v.0),
+            expected,
+            "{v}"
+        );
+    }
+    // the plain integer types truncate the floats
+    s.do_string("v = 3.9").unwrap();
+    assert_eq!(s.global().getopt::<_, i32>("v"), Some(3));
+    assert_eq!(s.global().getopt::<_, CheckedInt<i32>>("v"), None);
+    s.do_string("v = 2^63").unwrap();
+    assert_eq!(s.global().getopt::<_, CheckedInt<i64>>("v"), None);
+    s.do_string("v = -128").unwrap();
+    assert_eq!(
+        s.global().getopt::<_, CheckedInt<i8>>("v"),
+        Some(CheckedInt(-128))
+    );
+
+    s.global().set("byte", RsFn::new(|b: Checked<u8>| *b));
+    s.do_string(
+        r#"
+        assert(byte(200) == 200)
+        assert(not pcall(byte, 256) and not pcall(byte, 1.5))
+    "#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn userdata_close() {
+    use core::cell::Cell;
+
+    struct Resource(Rc<Cell<u32>>);
+
+    impl UserData for Resource {
+        const CLOSE: bool = true;
+
+        fn close(&mut self, _: &State) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let closed = Rc::new(Cell::new(0));
+    let s = State::new();
+    s.open_base();
+    let c = closed.clone();
+    s.global()
+        .set("open", RsFn::new(move || Resource(c.clone())));
+    s.do_string(
+        r#"
+        do
+            local r <close> = open()
+        end
+        pcall(function()
+            local r <close> = open()
+            error('failed')
+        end)
+    "#,
+    )
+    .unwrap();
+    // closed when leaving the scope, also by an error
+    assert_eq!(closed.get(), 2);
+
+    let top = s.get_top();
+    s.push(Resource(closed.clone()));
+    s.to_close(-1);
+    assert_eq!(closed.get(), 2);
+    s.set_top(top);
+    assert_eq!(closed.get(), 3);
+}
+
+#[test]
+fn deep_copy_merge() {
+    let s = State::new();
+    s.open_base();
+    s.do_string(
+        r#"
+        local shared = {1, 2}
+        src = setmetatable({a = {b = {c = 1}}, x = shared, y = shared}, {})
+        src.self = src
+        dst = {a = 'old', z = 3}
+    "#,
+    )
+    .unwrap();
+    let top = s.get_top();
+    let g = s.global();
+    s.global().set("copy", g.get("src").deep_copy());
+    // the other values are copied as is
+    let z = g.get("dst").get("z").deep_copy();
+    assert_eq!(z.cast::<i64>(), Some(3));
+    s.do_string(
+        r#"
+        assert(copy ~= src and copy.a ~= src.a and copy.a.b ~= src.a.b and copy.a.b.c == 1)
+        -- the cycle refers to the copy, and the shared table is copied once
+        assert(copy.self == copy)
+        assert(copy.x ~= src.x and copy.x == copy.y and copy.x[2] == 2)
+        assert(getmetatable(copy) == getmetatable(src))
+        copy.a.b.c = 2
+        assert(src.a.b.c == 1)
+    "#,
+    )
+    .unwrap();
+
+    let dst = g.get("dst");
+    dst.merge(&g.get("copy"), false);
+    s.do_string("assert(dst.a == 'old' and dst.z == 3 and dst.x == copy.x and dst.self == copy)")
+        .unwrap();
+    dst.merge(&g.get("src"), true);
+    s.do_string("assert(dst.a == src.a and dst.z == 3 and dst.x == src.x)")
+        .unwrap();
+    s.set_top(top);
+}
+
+#[test]
+fn coroutine_close() {
+    let s = State::new();
+    s.open_libs();
+    s.do_string(
+        r#"
+        closed = 0
+        function guard(fail)
+            return setmetatable({}, {__close = function()
+                closed = closed + 1
+                if fail then error('close failed') end
+            end})
+        end
+    "#,
+    )
+    .unwrap();
+
+    // the pending <close> variables of a suspended coroutine are closed
+    s.load_string("local a <close> = guard() local b <close> = guard() coroutine.yield(1)")
+        .unwrap();
+    let mut co = Coroutine::with_fn(&s, -1);
+    assert_eq!(co.resume_all::<_, i64>(()).unwrap(), [1]);
+    assert_eq!(s.global().getopt::<_, i64>("closed"), Some(0));
+    co.close().unwrap();
+    assert_eq!(s.global().getopt::<_, i64>("closed"), Some(2));
+    assert_eq!(co.status(), ThreadStatus::Ok);
+    assert_eq!(co.get_top(), 0);
+
+    // the error raised in __close is returned
+    s.load_string("local a <close> = guard(true) coroutine.yield()")
+        .unwrap();
+    let mut co = Coroutine::with_fn(&s, -1);
+    co.resume_all::<_, i64>(()).unwrap();
+    assert!(
+        matches!(co.close(), Err(crate::error::Error::Runtime(e)) if e.contains("close failed"))
+    );
+    assert_eq!(s.global().getopt::<_, i64>("closed"), Some(3));
+}
+
+#[test]
+fn set_index_fn() {
+    let s = State::new();
+    s.open_base();
+    s.do_string(
+        "plain = {x = 1} named = setmetatable({}, {__tostring = function() return 'named' end})",
+    )
+    .unwrap();
+    let g = s.global();
+    let top = s.get_top();
+
+    // the metatable is created if absent, and the existing fields are looked up first
+    g.get("plain")
+        .set_index_fn(|_: AnyVal, k: String| alloc::format!("dyn_{k}"));
+    g.get("named").set_index_fn(|_: AnyVal, k: String| k.len());
+    s.set_top(top);
+    s.do_string(
+        r#"
+        assert(plain.x == 1 and plain.y == 'dyn_y' and rawget(plain, 'y') == nil)
+        assert(named.abc == 3 and tostring(named) == 'named')
+    "#,
+    )
+    .unwrap();
+    assert_eq!(s.get_top(), top);
+}
+
+#[test]
+fn metafield() {
+    let s = State::new();
+    s.open_base();
+    s.do_string(
+        r#"
+        local mt = setmetatable({}, {__index = function() return 'inherited' end})
+        mt.__name = 'Point'
+        mt.__call = function(self, a, b) return self.x + a + b end
+        mt.__unm = function() error('unsupported') end
+        p = setmetatable({x = 1}, mt)
+        plain = {}
+    "#,
+    )
+    .unwrap();
+    let g = s.global();
+    let p = g.get("p");
+    let plain = g.get("plain");
+    let top = s.get_top();
+
+    assert_eq!(p.metafield("__name").unwrap().as_str(), Some("Point"));
+    s.set_top(top);
+    // the metamethods of the metatable aren't invoked
+    assert!(p.metafield("__len").is_none());
+    assert!(plain.metafield("__name").is_none());
+    assert_eq!(s.get_top(), top);
+
+    assert_eq!(p.call_meta::<_, i64>("__call", (2, 3)).unwrap().unwrap(), 6);
+    assert!(matches!(
+        p.call_meta::<_, ()>("__unm", ()),
+        Some(Err(crate::error::Error::Runtime(e))) if e.contains("unsupported")
+    ));
+    assert!(p.call_meta::<_, ()>("__len", ()).is_none());
+    assert!(plain.call_meta::<_, ()>("__call", ()).is_none());
+    assert_eq!(s.get_top(), top);
+}
+
+#[test]
+fn resume_all() {
+    let s = State::new();
+    s.open_libs();
+    s.load_string(
+        r#"
+        local a, b = ...
+        local c = coroutine.yield(a + b, a * b, 0)
+        coroutine.yield()
+        return c, 'done'
+    "#,
+    )
+    .unwrap();
+    let mut co = Coroutine::with_fn(&s, -1);
+    assert_eq!(co.resume_all::<_, i64>((2, 3)).unwrap(), [5, 6, 0]);
+    assert!(co.resume_all::<_, i64>(7).unwrap().is_empty());
+    // all the returned values are converted, the values are popped whatever the conversion
+    assert!(matches!(
+        co.resume_all::<_, i64>(()),
+        Err(crate::error::Error::ConvertFailed)
+    ));
+    assert_eq!(co.get_top(), 0);
+    assert!(co.resume_all::<_, i64>(()).is_err());
+
+    s.load_string("coroutine.yield() error('bad')").unwrap();
+    let mut co = Coroutine::with_fn(&s, -1);
+    co.resume_all::<_, String>(()).unwrap();
+    assert!(matches!(
+        co.resume_all::<_, String>(()),
+        Err(crate::error::Error::Runtime(e)) if e.contains("bad")
+    ));
+}
+
+#[test]
+fn coroutine_iter_yields() {
+    let s = State::new();
+    s.open_libs();
+    s.load_string("for i = 1, 3 do coroutine.yield(i, 'n' .. i) end return 0, 'end'")
+        .unwrap();
+    let co = Coroutine::with_fn(&s, -1);
+    // the returned values aren't iterated
+    let items = co
+        .iter_yields::<(i64, String)>()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        items,
+        [(1, "n1"), (2, "n2"), (3, "n3")].map(|(i, n)| (i, n.to_string()))
+    );
+
+    // the error ends the iteration
+    s.load_string("coroutine.yield(1) coroutine.yield('x') error('bad')")
+        .unwrap();
+    let mut iter = Coroutine::with_fn(&s, -1).iter_yields::<i64>();
+    assert_eq!(iter.next().unwrap().unwrap(), 1);
+    assert!(matches!(
+        iter.next(),
+        Some(Err(crate::error::Error::ConvertFailed))
+    ));
+    assert!(matches!(
+        iter.next(),
+        Some(Err(crate::error::Error::Runtime(e))) if e.contains("bad")
+    ));
+    assert!(iter.next().is_none());
+    assert_eq!(iter.coroutine().status(), ThreadStatus::RuntimeError);
+}
+
+#[test]
+fn value_len() {
+    let s = State::new();
+    s.open_base();
+    s.do_string(
+        r#"
+        list = {1, 2, 3}
+        empty = {}
+        hidden = setmetatable({1, 2}, {__len = function() return 0 end})
+        text = 'abc'
+    "#,
+    )
+    .unwrap();
+    let g = s.global();
+    let (list, empty, hidden, text) = (
+        g.get("list"),
+        g.get("empty"),
+        g.get("hidden"),
+        g.get("text"),
+    );
+    assert_eq!((list.len(), list.raw_len(), list.is_empty()), (3, 3, false));
+    assert_eq!(
+        (empty.len(), empty.raw_len(), empty.is_empty()),
+        (0, 0, true)
+    );
+    // only raw_len ignores __len
+    assert_eq!(
+        (hidden.len(), hidden.raw_len(), hidden.is_empty()),
+        (0, 2, true)
+    );
+    assert_eq!((text.len(), text.is_empty()), (3, false));
+}
+
+#[test]
+fn userdata_probe() {
+    let s = State::new();
+    s.open_base();
+    let t = s.table(0, 1);
+    t.set("ud", Test { a: 1 });
+    let top = s.get_top();
+
+    // a userdata of another type is rejected without leaving its metatable on the stack
+    t.get("ud");
+    assert!(s.arg::<&ByteView>(-1).is_none());
+    assert_eq!(s.get_top(), top + 1);
+    s.pop(1);
+    assert!(t.rawget_as::<_, &ByteView>("ud").is_none());
+    assert_eq!(s.get_top(), top);
+    assert_eq!(t.rawget_as::<_, &Test>("ud").map(|t| t.a), Some(1));
+    assert_eq!(s.get_top(), top);
+}
+
+#[test]
+fn string_to_number() {
+    let s = State::new();
+    let top = s.get_top();
+    assert_eq!(s.string_to_number("0x10"), 5);
+    assert_eq!(s.arg::<i64>(-1), Some(16));
+    s.pop(1);
+    assert_eq!(s.string_to_number("12\0abc"), 0);
+    assert_eq!(s.string_to_number("12abc"), 0);
+    assert_eq!(s.get_top(), top);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn val_index_check() {
+    let s = State::new();
+    s.push_nil();
+    s.val(1);
+    s.val(-1);
+    s.val(LUA_REGISTRYINDEX);
+    s.val(ffi::lua_upvalueindex(1));
+    for i in [0, 2, -2, LUA_REGISTRYINDEX + 1, ffi::lua_upvalueindex(256)] {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            s.val(i);
+        }));
+        assert!(result.is_err(), "{i}");
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+#[test]
+fn os_execute() {
+    let s = State::new();
+    s.open_libs();
+    crate::binding::init_global(&s);
+
+    let g = s.global();
+    for (cmd, ok, code) in [
+        ("true", true, 0),
+        ("exit 3", false, 3),
+        ("kill -9 $$", false, -9),
+    ] {
+        s.do_string(&alloc::format!("ok, code = os.execute('{cmd}')"))
+            .unwrap();
+        assert_eq!(g.getopt::<_, bool>("ok"), Some(ok), "{cmd}");
+        assert_eq!(g.getopt::<_, i64>("code"), Some(code), "{cmd}");
+    }
+    s.do_string("ok = os.execute()").unwrap();
+    assert_eq!(g.getopt::<_, bool>("ok"), Some(true));
 }
@@ -1,6 +1,6 @@
 pub use corepack;
 
-use crate::{serde::*, *};
+use crate::{serde::*, str::*, *};
 use ::serde::Deserializer;
 use corepack::{error, read};
 
@@ -72,3 +72,78 @@ impl State {
         binding::init_global(&s);
     }
 }
+
+/// Iterator over the captures of `string.gmatch`, created by [`State::lua_gmatch`]
+pub struct LuaGmatch<'a> {
+    state: &'a State,
+    iter: Reference,
+}
+
+impl Iterator for LuaGmatch<'_> {
+    type Item = Vec<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.state;
+        let top = s.get_top();
+        s.raw_geti(LUA_REGISTRYINDEX, self.iter.value() as _);
+        let result = (s.pcall(0, ffi::LUA_MULTRET, 0) == ThreadStatus::Ok
+            && !s.is_none_or_nil(top + 1))
+        .then(|| s.captures(top + 1));
+        s.set_top(top);
+        result
+    }
+}
+
+impl Drop for LuaGmatch<'_> {
+    fn drop(&mut self) {
+        self.state.unreference(LUA_REGISTRYINDEX, self.iter);
+    }
+}
+
+impl State {
+    /// Calls `string.match(s, pattern)`, returns the captures or `None` if not matched
+    pub fn lua_match(&self, s: &str, pattern: &str) -> Option<Vec<String>> {
+        let top = self.get_top();
+        let result = (self.string_fn(cstr!("match"))
+            && self.pushx((s, pattern)) > 0
+            && self.pcall(2, ffi::LUA_MULTRET, 0) == ThreadStatus::Ok
+            && !self.is_none_or_nil(top + 1))
+        .then(|| self.captures(top + 1));
+        self.set_top(top);
+        result
+    }
+
+    /// Calls `string.gmatch(s, pattern)`, returns an iterator over the captures of each match
+    pub fn lua_gmatch(&self, s: &str, pattern: &str) -> Option<LuaGmatch> {
+        let top = self.get_top();
+        if self.string_fn(cstr!("gmatch"))
+            && self.pushx((s, pattern)) > 0
+            && self.pcall(2, 1, 0) == ThreadStatus::Ok
+        {
+            return Some(LuaGmatch {
+                state: self,
+                iter: self.reference(LUA_REGISTRYINDEX),
+            });
+        }
+        self.set_top(top);
+        None
+    }
+
+    /// [-0, +1, -] Pushes the function `string[name]`
+    fn string_fn(&self, name: &CStr) -> bool {
+        self.get_global(cstr!("string"));
+        if self.type_of(-1) == Type::Table {
+            self.get_field(-1, name);
+            self.remove(-2);
+            self.is_function(-1)
+        } else {
+            false
+        }
+    }
+
+    fn captures(&self, begin: Index) -> Vec<String> {
+        (begin..=self.get_top())
+            .map(|i| self.to_str(i).unwrap_or_default().to_string())
+            .collect()
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{str::*, *};
+use crate::{error::Error, str::*, *};
+use alloc::format;
 
 pub use crate::ffi::{
     lua_Alloc, lua_CFunction, lua_Hook, lua_Integer, lua_Number, CFunction, LUA_REGISTRYINDEX,
@@ -12,7 +13,24 @@ pub struct ValRef<'a> {
 }
 
 impl<'a> ValRef<'a> {
+    /// In debug builds, panics if `index` isn't in `[1, top]` or `[-top, -1]`,
+    /// and isn't [`LUA_REGISTRYINDEX`] or an upvalue index
     pub fn new(state: &'a State, index: Index) -> Self {
+        #[cfg(debug_assertions)]
+        {
+            let top = state.get_top();
+            // `MAXUPVAL` of lfunc.h is 255
+            let upvalue = (1..=255).contains(&(LUA_REGISTRYINDEX - index));
+            assert!(
+                (1..=top).contains(&index)
+                    || (-top..=-1).contains(&index)
+                    || index == LUA_REGISTRYINDEX
+                    || upvalue,
+                "invalid stack index {} for ValRef, the stack top is {}",
+                index,
+                top
+            );
+        }
         ValRef {
             state,
             index: state.abs_index(index),
@@ -24,6 +42,12 @@ impl<'a> ValRef<'a> {
         self.state.type_of(self.index)
     }
 
+    /// The lua type name of this value, see [`State::typename_at`]
+    #[inline]
+    pub fn type_name(&self) -> Cow<'a, str> {
+        self.state.typename_at(self.index)
+    }
+
     #[inline]
     pub fn is_nil(&self) -> bool {
         self.state.is_nil(self.index)
@@ -34,6 +58,46 @@ impl<'a> ValRef<'a> {
         self.state.is_integer(self.index)
     }
 
+    /// Whether this value is a function or has a `__call` metamethod
+    #[inline]
+    pub fn is_callable(&self) -> bool {
+        self.state.is_function(self.index) || {
+            let has_call = self.state.get_metafield(self.index, cstr!("__call"));
+            if has_call {
+                self.state.pop(1);
+            }
+            has_call
+        }
+    }
+
+    /// The content of a string value, numbers aren't converted, `None` also for invalid UTF-8
+    #[inline]
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes().and_then(|b| core::str::from_utf8(b).ok())
+    }
+
+    /// The content of a string value, numbers aren't converted
+    #[inline]
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        if self.type_of() == Type::String {
+            self.state.to_bytes(self.index)
+        } else {
+            None
+        }
+    }
+
+    /// Maps to `lua_tointegerx`, strings convertible to an integer are accepted
+    #[inline]
+    pub fn as_integer(&self) -> Option<lua_Integer> {
+        self.state.to_integerx(self.index)
+    }
+
+    /// Maps to `lua_tonumberx`, strings convertible to a number are accepted
+    #[inline]
+    pub fn as_number(&self) -> Option<lua_Number> {
+        self.state.to_numberx(self.index)
+    }
+
     #[inline]
     pub fn to_bool(&self) -> bool {
         self.state.to_bool(self.index)
@@ -54,9 +118,12 @@ impl<'a> ValRef<'a> {
         T::check(self.state, self.index)
     }
 
+    /// [-0, +1, e]
     pub fn geti(&self, i: impl Into<lua_Integer>) -> ValRef {
-        self.state.geti(self.index, i.into());
-        self.state.val(-1)
+        stack_delta!("ValRef::geti", self.state, 1, {
+            self.state.geti(self.index, i.into());
+            self.state.val(-1)
+        })
     }
 
     pub fn seti<V: ToLua>(&self, i: impl Into<lua_Integer>, v: V) {
@@ -64,22 +131,69 @@ impl<'a> ValRef<'a> {
         self.state.seti(self.index, i.into());
     }
 
+    /// [-0, +1, e]
     pub fn getf(&self, k: &CStr) -> ValRef {
-        self.state.get_field(self.index, k);
-        self.state.val(-1)
+        stack_delta!("ValRef::getf", self.state, 1, {
+            self.state.get_field(self.index, k);
+            self.state.val(-1)
+        })
     }
 
+    /// [-0, +1, -], or [-1, +1, -] if the key is [`TopVal`]
     #[inline]
     pub fn rawget<K: ToLua>(&self, k: K) -> Type {
-        self.state.push(k);
-        self.state.raw_get(self.index)
+        stack_delta!("ValRef::rawget", self.state, !K::IS_TOP as i32, {
+            self.state.push(k);
+            self.state.raw_get(self.index)
+        })
     }
 
+    /// Like [`ValRef::getopt`], but bypasses the metamethods
     #[inline]
-    pub fn rawlen(&self) -> usize {
+    pub fn rawget_as<K: ToLua, V: FromLua<'a>>(&self, k: K) -> Option<V> {
+        self.rawget(k);
+        let res = V::from_lua(self.state, -1);
+        self.state.pop(1);
+        res
+    }
+
+    /// The length without invoking the `__len` metamethod, a border for tables, see [`State::raw_len`]
+    #[inline]
+    pub fn raw_len(&self) -> usize {
         self.state.raw_len(self.index)
     }
 
+    /// The length as the `#` operator, may invoke the `__len` metamethod, see [`State::len_direct`]
+    #[inline]
+    pub fn len(&self) -> lua_Integer {
+        self.state.len_direct(self.index)
+    }
+
+    /// Whether [`ValRef::len`] is zero, may invoke the `__len` metamethod as well
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Dumps the function as a binary chunk which can be loaded by [`State::load_buffer`],
+    /// `None` if it's not a lua function
+    pub fn dump_bytecode(&self, strip: bool) -> Option<Vec<u8>> {
+        if !self.state.is_function(self.index) {
+            return None;
+        }
+        let mut buf = Vec::new();
+        self.state.push_value(self.index);
+        let status = self.state.dump(|b| buf.extend_from_slice(b), strip);
+        self.state.pop(1);
+        (status == 0).then_some(buf)
+    }
+
+    #[deprecated(note = "use ValRef::raw_len")]
+    #[inline]
+    pub fn rawlen(&self) -> usize {
+        self.raw_len()
+    }
+
     #[inline]
     pub fn set_field(&self, k: &CStr) {
         self.state.set_field(self.index, k);
@@ -91,10 +205,34 @@ impl<'a> ValRef<'a> {
         self.set_field(k);
     }
 
+    /// [-0, +1, e] Like [`ValRef::getf`], but the key needn't be NUL-terminated
+    #[inline]
+    pub fn get_str_field(&self, k: &str) -> ValRef<'a> {
+        self.get(k)
+    }
+
+    /// [-0, +0, e] Like [`ValRef::setf`], but the key needn't be NUL-terminated
+    #[inline]
+    pub fn set_str_field<V: ToLua>(&self, k: &str, v: V) {
+        self.set(k, v)
+    }
+
+    /// [-0, +1, -]
     #[inline]
     pub fn getp<T>(&self, p: *const T) -> ValRef {
+        stack_delta!("ValRef::getp", self.state, 1, {
+            self.state.raw_getp(self.index, p);
+            self.state.val(-1)
+        })
+    }
+
+    /// Like [`ValRef::rawget_as`], but the key is the light userdata `p`
+    #[inline]
+    pub fn getp_as<T, V: FromLua<'a>>(&self, p: *const T) -> Option<V> {
         self.state.raw_getp(self.index, p);
-        self.state.val(-1)
+        let res = V::from_lua(self.state, -1);
+        self.state.pop(1);
+        res
     }
 
     #[inline]
@@ -126,11 +264,14 @@ impl<'a> ValRef<'a> {
         self.state.set_table(self.index);
     }
 
+    /// [-0, +1, e], or [-1, +1, e] if the key is [`TopVal`]
     #[inline]
     pub fn get<K: ToLua>(&self, k: K) -> ValRef<'a> {
-        self.state.push(k);
-        self.state.get_table(self.index);
-        self.state.val(-1)
+        stack_delta!("ValRef::get", self.state, !K::IS_TOP as i32, {
+            self.state.push(k);
+            self.state.get_table(self.index);
+            self.state.val(-1)
+        })
     }
 
     #[inline]
@@ -140,6 +281,186 @@ impl<'a> ValRef<'a> {
         self.state.pop(1);
         res
     }
+
+    /// [-0, +1, m] Copies the table recursively, a nested table referenced multiple times is copied once,
+    /// other values, table keys and metatables are shared with the original
+    pub fn deep_copy(&self) -> ValRef<'a> {
+        let s = self.state;
+        if self.type_of() != Type::Table {
+            s.push_value(self.index);
+            return s.val(-1);
+        }
+        // maps the original tables to their copies
+        s.new_table();
+        let seen = s.get_top();
+        Self::copy_table(s, self.index, seen);
+        s.remove(seen);
+        s.val(-1)
+    }
+
+    // [-0, +1]
+    fn copy_table(s: &State, src: Index, seen: Index) {
+        s.push_value(src);
+        if s.raw_get(seen) == Type::Table {
+            return;
+        }
+        s.pop(1);
+        s.check_stack(4);
+        s.new_table();
+        let dst = s.get_top();
+        s.push_value(src);
+        s.push_value(dst);
+        s.raw_set(seen);
+
+        s.push_nil();
+        while s.next(src) {
+            if s.type_of(-1) == Type::Table {
+                Self::copy_table(s, s.abs_index(-1), seen);
+                s.replace(-2);
+            }
+            s.push_value(-2);
+            s.insert(-2);
+            s.raw_set(dst);
+        }
+        if s.get_metatable(src) {
+            s.set_metatable(dst);
+        }
+    }
+
+    /// [-0, +0, m] Copies the fields of `other` into this table, the existing fields are replaced only if `overwrite`
+    pub fn merge(&self, other: &ValRef, overwrite: bool) {
+        let s = self.state;
+        s.check_stack(4);
+        s.push_nil();
+        while s.next(other.index) {
+            if !overwrite {
+                s.push_value(-2);
+                if s.raw_get(self.index) != Type::Nil {
+                    s.pop(2);
+                    continue;
+                }
+                s.pop(1);
+            }
+            s.push_value(-2);
+            s.insert(-2);
+            s.raw_set(self.index);
+        }
+    }
+
+    /// [-0, +1, e] Performs `self op rhs` by `lua_arith` honoring the metamethods,
+    /// `rhs` is ignored if `op` is unary ([`Arithmetic::Unm`] or [`Arithmetic::BNot`])
+    pub fn arith(&self, op: Arithmetic, rhs: &ValRef) -> ValRef<'a> {
+        let s = self.state;
+        s.check_stack(2);
+        s.push_value(self.index);
+        if !matches!(op, Arithmetic::Unm | Arithmetic::BNot) {
+            s.push_value(rhs.index);
+        }
+        s.arith(op);
+        s.val(-1)
+    }
+
+    /// [-0, +0, e] Compares `self op other` by `lua_compare` honoring the metamethods
+    #[inline]
+    pub fn compare(&self, other: &ValRef, op: Comparison) -> bool {
+        self.state.compare(self.index, other.index, op)
+    }
+
+    /// Calls `self:name(args...)` in protected mode, the method is looked up through `__index`,
+    /// and the errors raised by the lookup are returned as well
+    pub fn call_method<A: ToLuaMulti, R: FromLuaMulti<'a>>(
+        &self,
+        name: &str,
+        args: A,
+    ) -> Result<R, Error> {
+        // [self, name, args...] => self[name](self, args...)
+        unsafe extern "C" fn call_method(l: *mut ffi::lua_State) -> libc::c_int {
+            let s = State::from_ptr(l);
+            s.push_value(2);
+            if s.get_table(1).is_none_or_nil() {
+                let msg = format!("method '{}' not found", s.to_str(2).unwrap_or_default());
+                s.error_string(msg);
+            }
+            s.replace(2);
+            s.push_value(1);
+            s.copy(2, 1);
+            s.replace(2);
+            s.call(s.get_top() - 1, ffi::LUA_MULTRET);
+            s.get_top()
+        }
+
+        let s = self.state;
+        let top = s.get_top();
+        s.push_fn(Some(State::traceback_c));
+        s.push_fn(Some(call_method));
+        s.push_value(self.index);
+        s.push(name);
+        let nargs = s.pushx(args) + 2;
+        let result = s
+            .to_error(s.pcall(nargs, R::COUNT as _, top + 1))
+            .and_then(|_| R::from_lua(s, top + 2).ok_or(Error::ConvertFailed));
+        s.set_top(top);
+        result
+    }
+
+    /// [-0, +(0|1), -] Gets the field `name` of the metatable without invoking metamethods,
+    /// `None` if there is no metatable or the field is nil
+    pub fn metafield(&self, name: &str) -> Option<ValRef<'a>> {
+        let s = self.state;
+        if !s.get_metatable(self.index) {
+            return None;
+        }
+        s.push(name);
+        if s.raw_get(-2).is_none_or_nil() {
+            s.pop(2);
+            return None;
+        }
+        s.remove(-2);
+        Some(s.val(-1))
+    }
+
+    /// Calls the metafield `name` with this value and `args` in protected mode, `None` if there is no such metafield
+    pub fn call_meta<A: ToLuaMulti, R: FromLuaMulti<'a>>(
+        &self,
+        name: &str,
+        args: A,
+    ) -> Option<Result<R, Error>> {
+        let s = self.state;
+        let top = s.get_top();
+        s.push_fn(Some(State::traceback_c));
+        if self.metafield(name).is_none() {
+            s.set_top(top);
+            return None;
+        }
+        s.push_value(self.index);
+        let nargs = s.pushx(args) + 1;
+        let result = s
+            .to_error(s.pcall(nargs, R::COUNT as _, top + 1))
+            .and_then(|_| R::from_lua(s, top + 2).ok_or(Error::ConvertFailed));
+        s.set_top(top);
+        Some(result)
+    }
+
+    /// Calls this value with `args` in protected mode, and converts all of its results whatever the count
+    pub fn call_multi<A: ToLuaMulti, T: for<'b> FromLua<'b>>(
+        &self,
+        args: A,
+    ) -> Result<Vec<T>, Error> {
+        let s = self.state;
+        let top = s.get_top();
+        s.push_fn(Some(State::traceback_c));
+        s.push_value(self.index);
+        let nargs = s.pushx(args);
+        let result = s
+            .to_error(s.pcall(nargs, ffi::LUA_MULTRET, top + 1))
+            .and_then(|_| {
+                (top + 2..=s.get_top())
+                    .map(|i| T::from_lua(s, i).ok_or(Error::ConvertFailed))
+                    .collect()
+            });
+        s.set_top(top);
+        result
+    }
 }
 
 #[derive(Deref)]
@@ -153,7 +474,8 @@ impl Coroutine {
         let result = s.new_thread();
         assert!(s.type_of(-1) == Type::Thread);
         s.raw_setp(LUA_REGISTRYINDEX, result.as_ptr());
-        Self(result)
+        // anchored by the registry until dropped, the inner `State` is never given out to be closed
+        Self(unsafe { State::from_raw(result.as_ptr()) })
     }
 
     pub fn with_fn(s: &State, i: Index) -> Self {
@@ -166,6 +488,115 @@ impl Coroutine {
     }
 }
 
+impl Coroutine {
+    /// Maps to `lua_resetthread` (`lua_closethread` since lua 5.4.6), closes the pending
+    /// to-be-closed variables and resets the coroutine to an empty one, which dropping doesn't do.
+    /// Returns the error of the coroutine or the error raised in a `__close` metamethod
+    pub fn close(&mut self) -> Result<(), Error> {
+        let status = ThreadStatus::from_c_int(unsafe { ffi::lua_resetthread(self.as_ptr()) });
+        let result = self.to_error(status);
+        self.set_top(0);
+        result
+    }
+
+    /// Resumes the coroutine with `args`, and converts all the values it yielded or returned
+    pub fn resume_all<A: ToLuaMulti, T: for<'b> FromLua<'b>>(
+        &mut self,
+        args: A,
+    ) -> Result<Vec<T>, Error> {
+        let nargs = self.pushx(args);
+        let mut nres = 0;
+        match self.resume(None, nargs, &mut nres) {
+            ThreadStatus::Ok | ThreadStatus::Yield => {
+                let base = self.get_top() - nres + 1;
+                let result = (base..base + nres)
+                    .map(|i| T::from_lua(self, i).ok_or(Error::ConvertFailed))
+                    .collect();
+                self.pop(nres);
+                result
+            }
+            status => self.to_error(status).map(|_| Vec::new()),
+        }
+    }
+
+    /// Resumes the coroutine on each `next()` and yields the values it yielded,
+    /// the iteration ends when the coroutine returns or raises an error
+    pub fn iter_yields<T: for<'b> FromLuaMulti<'b>>(self) -> CoroutineIter<T> {
+        CoroutineIter {
+            co: self,
+            done: false,
+            _item: Default::default(),
+        }
+    }
+}
+
+/// An iterator over the values yielded by a [`Coroutine`], see [`Coroutine::iter_yields`]
+pub struct CoroutineIter<T> {
+    co: Coroutine,
+    done: bool,
+    _item: core::marker::PhantomData<fn() -> T>,
+}
+
+impl<T> CoroutineIter<T> {
+    #[inline(always)]
+    pub fn coroutine(&self) -> &Coroutine {
+        &self.co
+    }
+}
+
+impl<T: for<'b> FromLuaMulti<'b>> Iterator for CoroutineIter<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut nres = 0;
+        match self.co.resume(None, 0, &mut nres) {
+            ThreadStatus::Yield => {
+                let base = self.co.get_top() - nres + 1;
+                let result = T::from_lua(&self.co, base).ok_or(Error::ConvertFailed);
+                self.co.pop(nres);
+                Some(result)
+            }
+            ThreadStatus::Ok => {
+                self.done = true;
+                self.co.pop(nres);
+                None
+            }
+            status => {
+                self.done = true;
+                self.co.to_error(status).err().map(Err)
+            }
+        }
+    }
+}
+
+/// A [`Coroutine`] which can't outlive the state it was created from
+#[derive(Deref)]
+pub struct ScopedCoroutine<'s> {
+    #[deref]
+    co: Coroutine,
+    _state: core::marker::PhantomData<&'s State>,
+}
+
+impl<'s> ScopedCoroutine<'s> {
+    // [-0, +0]
+    pub fn empty(s: &'s State) -> Self {
+        Self {
+            co: Coroutine::empty(s),
+            _state: Default::default(),
+        }
+    }
+
+    pub fn with_fn(s: &'s State, i: Index) -> Self {
+        Self {
+            co: Coroutine::with_fn(s, i),
+            _state: Default::default(),
+        }
+    }
+}
+
 impl FromLua<'_> for Coroutine {
     fn from_lua(s: &State, i: Index) -> Option<Self> {
         match s.type_of(i) {
@@ -179,6 +610,9 @@ impl FromLua<'_> for Coroutine {
 
 impl Drop for Coroutine {
     fn drop(&mut self) {
+        // the frame of an errored coroutine may have no free slot
+        self.check_stack(2);
+        self.drop_pending_tasks();
         self.push_nil();
         self.raw_setp(LUA_REGISTRYINDEX, self.as_ptr());
     }
@@ -6,10 +6,9 @@ async fn llua_async() {
     s.open_libs();
 
     let g = s.global();
-    g.register(
-        "echo_async",
-        |s: State, n: i32| async move { s.pushed((0, n)) },
-    );
+    g.register("echo_async", |s: StateRef<'static>, n: i32| async move {
+        s.pushed((0, n))
+    });
     g.register("sleep_async", tokio::time::sleep);
 
     let co = Coroutine::empty(&s);
@@ -28,3 +27,157 @@ async fn llua_async() {
     let ret = co.call_async::<_, (i32, i32)>(333, None).await.unwrap();
     assert_eq!(ret, (1, 2));
 }
+
+#[tokio::test]
+async fn state_call_async() {
+    let s = State::new();
+    s.open_libs();
+    s.global().register("sleep_async", tokio::time::sleep);
+    s.set_top(0);
+
+    s.load_string("sleep_async(0.01); return ... .. '!', 2")
+        .unwrap();
+    let ret = s.call_async::<_, (&str, i32)>("done").await.unwrap();
+    assert_eq!(ret, ("done!", 2));
+    assert_eq!(s.get_top(), 2);
+}
+
+#[tokio::test]
+async fn async_owned_args() {
+    let s = State::new();
+    s.open_libs();
+    s.global()
+        .register("concat_async", |a: String, b: String| async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            a + &b
+        });
+    s.set_top(0);
+
+    s.load_string("return concat_async(...)").unwrap();
+    let ret = s.call_async::<_, String>(("ab", "cd")).await.unwrap();
+    assert_eq!(ret, "abcd");
+}
+
+#[tokio::test]
+async fn async_channel() {
+    let s = State::new();
+    s.open_libs();
+    let (tx, rx) = channel::<String>();
+    s.global().set("rx", rx);
+    s.global().set("tx", tx.clone());
+    s.set_top(0);
+    s.load_string(
+        "local a = rx:recv(); local b = rx:recv(); tx:send('x'); return a .. b .. rx:recv()",
+    )
+    .unwrap();
+    let h = tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        tx.send("hello ".into()).ok();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        tx.send("world".into()).ok();
+    });
+    let r = s.call_async::<_, String>(()).await.unwrap();
+    assert_eq!(r, "hello worldx");
+    h.await.unwrap();
+}
+
+#[tokio::test]
+async fn async_mutex_lock() {
+    let s = State::new();
+    s.open_libs();
+    s.global().register("sleep_async", tokio::time::sleep);
+    s.global().set("m", async_mutex());
+    s.do_string("log = ''").unwrap();
+
+    let task = |name: &str| {
+        let co = Coroutine::empty(&s);
+        co.load_string(
+            "
+            local name = ...
+            local guard <close> = m:lock()
+            log = log .. name
+            sleep_async(0.01)
+            log = log .. name:upper()
+        ",
+        )
+        .unwrap();
+        let name = name.to_string();
+        async move { co.call_async::<_, ()>(name, None).await.unwrap() }
+    };
+    tokio::join!(task("a"), task("b"));
+    s.do_string("assert(log == 'aAbB', log); assert(not m:is_locked())")
+        .unwrap();
+}
+
+#[cfg(all(unix, feature = "async"))]
+#[tokio::test]
+async fn command_output_async() {
+    let s = State::new();
+    s.open_libs();
+    s.init_llua_global();
+    s.set_top(0);
+
+    s.load_string(
+        "return os.command('sh'):args{'-c', 'echo out; echo err >&2; exit 3'}:output_async()",
+    )
+    .unwrap();
+    let ret = s
+        .call_async::<_, (bool, i32, String, String)>(())
+        .await
+        .unwrap();
+    assert_eq!(ret, (false, 3, "out\n".into(), "err\n".into()));
+}
+
+#[tokio::test]
+async fn coroutine_drop_pending_task() {
+    use std::rc::Rc;
+
+    struct Guard(Rc<core::cell::Cell<bool>>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    let dropped = Rc::new(core::cell::Cell::new(false));
+    let s = State::new();
+    s.open_base();
+    let flag = dropped.clone();
+    s.global().register("pending_async", move || {
+        let guard = Guard(flag.clone());
+        async move {
+            let _guard = guard;
+            std::future::pending::<()>().await
+        }
+    });
+    s.set_top(0);
+
+    s.load_string("pending_async()").unwrap();
+    let co = Coroutine::with_fn(&s, -1);
+    let mut nres = 0;
+    assert_eq!(co.resume(None, 0, &mut nres), ThreadStatus::Yield);
+    assert!(!dropped.get());
+    drop(co);
+    assert!(dropped.get());
+}
+
+#[test]
+fn coroutine_drop_pending_recv() {
+    let s = State::new();
+    s.open_base();
+    let (tx, rx) = channel::<i32>();
+    s.global().set("rx", rx);
+    s.set_top(0);
+
+    // extra userdata arguments, every mismatched probe used to leave 2 values on the stack
+    let args = vec!["rx"; 16].join(", ");
+    s.load_string(&format!("return rx.recv({args})")).unwrap();
+    let co = Coroutine::with_fn(&s, -1);
+    let mut nres = 0;
+    assert_eq!(co.resume(None, 0, &mut nres), ThreadStatus::Yield);
+    drop(co);
+    s.global().set("rx", ());
+    s.collect_full();
+    assert!(tx.send(1).is_err());
+}
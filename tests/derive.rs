@@ -0,0 +1,64 @@
+#![cfg(feature = "derive")]
+
+use llua::*;
+
+#[derive(FromLua, Debug, PartialEq)]
+enum Mode {
+    Read,
+    #[llua(rename = "w")]
+    Write,
+}
+
+#[derive(FromLua, serde::Deserialize, Debug, PartialEq)]
+enum Shape {
+    Circle { r: f64 },
+}
+
+#[test]
+fn derive_from_lua() {
+    let s = State::new();
+    s.open_libs();
+    s.do_string("mode, bad, shape = 'w', 'Write', {Circle = {r = 1.5}}")
+        .unwrap();
+    let g = s.global();
+    assert_eq!(g.getopt::<_, Mode>("mode"), Some(Mode::Write));
+    assert_eq!(g.getopt::<_, Mode>("bad"), None);
+    assert_eq!(
+        g.getopt::<_, Shape>("shape"),
+        Some(Shape::Circle { r: 1.5 })
+    );
+    assert_eq!(<Mode as FromLua>::TYPE_NAME, "Read|w");
+}
+
+#[derive(ToLua)]
+struct Config {
+    name: &'static str,
+    #[llua(rename = "max")]
+    limit: i32,
+    #[llua(skip)]
+    _secret: u64,
+}
+
+#[derive(ToLua)]
+struct Point(i32, i32);
+
+#[test]
+fn derive_to_lua() {
+    let s = State::new();
+    s.open_libs();
+    let g = s.global();
+    g.set(
+        "config",
+        Config {
+            name: "llua",
+            limit: 3,
+            _secret: 0,
+        },
+    );
+    g.set("point", Point(1, 2));
+    s.do_string(
+        "assert(config.name == 'llua' and config.max == 3 and config._secret == nil)
+        assert(#point == 2 and point[1] == 1 and point[2] == 2)",
+    )
+    .unwrap();
+}
@@ -21,4 +21,9 @@ thread.spawn(function()
 end)
 
 thread.sleep(100)
-cond:notify_one('notify: 111')
\ No newline at end of file
+cond:notify_one('notify: 111')
+local t = thread.spawn(function()
+    return 1, 'abc', { x = 2 }
+end)
+local a, b, c = t:join()
+assert(a == 1 and b == 'abc' and c.x == 2)